@@ -1,21 +1,27 @@
+use crate::cassette::{record_response, replay_response};
 use crate::constants::{MAX_TOKENS, OPENAI_ENDPOINT};
+use crate::cost::{estimate_image_tokens, record_usage};
 use crate::parsers::{format_vision_prompt, get_last_assistant_message};
-use crate::screen::{add_grid_to_image, capture_screen_with_cursor};
+use crate::screen::{
+    add_grid_to_image, add_marks_to_image, add_pointer_to_image,
+    add_previous_action_to_annotated_image, auto_grid_interval, capture_screen,
+};
 use crate::types::{
-    ImageMessage, ImageMessageContent, ImageUrl, Message, OpenAIRequest, Role, TextMessage,
+    Annotation, Config, GridOptions, ImageMessage, ImageMessageContent, ImageUrl, Message,
+    ModelCall, Observation, OpenAIRequest, PreviousStep, Role, TextMessage, TokenUsage,
 };
+use crate::window::{active_window, list_windows, resolve_region};
 use base64::{engine::general_purpose, Engine as _};
 use reqwest::Client;
 use serde_json::Value;
-use std::{env, fs, path::Path, thread, time::Duration};
+use std::{env, fs, path::Path, time::Instant};
 
 pub async fn get_next_action_from_openai(
     messages: &mut Vec<Message>,
     objective: &str,
-    grid_interval: i32,
-) -> Result<String, String> {
-    thread::sleep(Duration::from_secs(1));
-
+    config: &Config,
+    previous_step: Option<&PreviousStep>,
+) -> Result<(String, Observation, ModelCall), String> {
     let screenshots_dir = "screenshots";
     if !Path::new(screenshots_dir).exists() {
         fs::create_dir(screenshots_dir)
@@ -23,31 +29,98 @@ pub async fn get_next_action_from_openai(
     }
 
     let screenshot_filename = format!("{}/screenshot.png", screenshots_dir);
-    capture_screen_with_cursor(&screenshot_filename)
+    let region = resolve_region(config.region.as_ref())?;
+    let capture = capture_screen(&screenshot_filename, region.as_ref())
         .map_err(|e| format!("Error capturing screen: {}", e))?;
+    let redactions = match &config.redactor {
+        Some(redactor) => redactor
+            .redact(&screenshot_filename, region.as_ref())
+            .map_err(|e| format!("Error redacting the screenshot: {}", e))?,
+        None => Vec::new(),
+    };
+
+    let (width, height) = image::image_dimensions(&screenshot_filename)
+        .map_err(|e| format!("Error reading screenshot dimensions: {}", e))?;
+    let grid = GridOptions {
+        interval: if config.grid.auto_interval {
+            auto_grid_interval(width, height, config.grid.style)?
+        } else {
+            config.grid.interval
+        },
+        ..config.grid
+    };
 
     let new_screenshot_filename = format!("{}/screenshot_with_grid.png", screenshots_dir);
-    add_grid_to_image(
-        &screenshot_filename,
-        &new_screenshot_filename,
-        grid_interval,
-    )
-    .map_err(|e| format!("Error adding grid to image: {}", e))?;
+    let (marks, offset) = match config.annotation {
+        Annotation::Grid => {
+            let offset = add_grid_to_image(&screenshot_filename, &new_screenshot_filename, &grid)
+                .map_err(|e| format!("Error adding grid to image: {}", e))?;
+            (Vec::new(), offset)
+        }
+        Annotation::Marks => {
+            let marks = add_marks_to_image(&screenshot_filename, &new_screenshot_filename)
+                .map_err(|e| format!("Error adding marks to image: {}", e))?;
+            (marks, (0, 0))
+        }
+    };
+    // Drawn after the annotation so they are not taken for UI elements, `offset` places them
+    // on the screenshot when the grid added margins
+    if let Some(previous_action) = previous_step.and_then(|step| step.action.as_ref()) {
+        add_previous_action_to_annotated_image(
+            &new_screenshot_filename,
+            previous_action,
+            offset,
+            (width, height),
+        )
+        .map_err(|e| format!("Error drawing the previous action: {}", e))?;
+    }
+    if let Some((x, y)) = capture.pointer {
+        add_pointer_to_image(
+            &new_screenshot_filename,
+            (offset.0 + x, offset.1 + y),
+            capture.scale,
+        )
+        .map_err(|e| format!("Error drawing the pointer: {}", e))?;
+    }
 
-    thread::sleep(Duration::from_secs(1));
+    let observation = Observation {
+        screenshot_path: screenshot_filename,
+        annotated_path: new_screenshot_filename,
+        width,
+        height,
+        marks,
+        grid: match config.annotation {
+            Annotation::Grid => Some(grid),
+            Annotation::Marks => None,
+        },
+        pointer: capture.pointer,
+        previous_step: previous_step.cloned(),
+        region,
+        // The focused window is only described when it is in the list
+        windows: list_windows()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|window| match &config.redactor {
+                Some(redactor) => !redactor.hides_window(&window.title),
+                None => true,
+            })
+            .collect(),
+        focused_window: active_window().unwrap_or_default(),
+        redactions,
+    };
 
-    let img_file = fs::read(&new_screenshot_filename)
+    let img_file = fs::read(&observation.annotated_path)
         .map_err(|e| format!("Error reading screenshot file: {}", e))?;
     let img_base64 = general_purpose::STANDARD.encode(&img_file);
 
     let mut previous_action = get_last_assistant_message(messages);
-    let vision_prompt = format_vision_prompt(objective, &mut previous_action);
+    let vision_prompt = format_vision_prompt(objective, &mut previous_action, config, &observation);
 
     let vision_message = Message::ImageMessage(ImageMessage {
         role: Role::User,
         content: vec![
             ImageMessageContent::Text {
-                text: vision_prompt,
+                text: vision_prompt.clone(),
             },
             ImageMessageContent::ImageUrl {
                 image_url: ImageUrl {
@@ -66,9 +139,16 @@ pub async fn get_next_action_from_openai(
         max_tokens: MAX_TOKENS,
     };
 
-    let content = send_message_to_openai(payload)
+    let request_start = Instant::now();
+    let (content, usage) = send_message_to_openai(payload)
         .await
         .map_err(|e| format!("Error sending message to OpenAI: {}", e))?;
+    let call = ModelCall {
+        prompt: vision_prompt,
+        response: content.clone(),
+        duration_ms: request_start.elapsed().as_millis() as u64,
+        usage,
+    };
 
     messages.push(Message::TextMessage(TextMessage {
         role: Role::User,
@@ -80,31 +160,52 @@ pub async fn get_next_action_from_openai(
         content: content.to_string(),
     }));
 
-    Ok(content.replace("\\", ""))
+    Ok((content.replace("\\", ""), observation, call))
 }
 
-pub async fn send_message_to_openai(payload: OpenAIRequest) -> Result<String, String> {
+/// Sends a chat completion request, returning the answer and the token usage if the API
+/// reported it.
+pub async fn send_message_to_openai(
+    payload: OpenAIRequest,
+) -> Result<(String, Option<TokenUsage>), String> {
+    let request = serde_json::to_value(&payload)
+        .map_err(|e| format!("Failed to serialize payload: {}", e))?;
+    let image_tokens = estimate_image_tokens(&payload.messages);
+
+    let (response, replayed) = match replay_response(&request)? {
+        Some(response) => (response, true),
+        None => {
+            let response = post_to_openai(&request).await?;
+            record_response(&request, &response)?;
+            (response, false)
+        }
+    };
+
+    let content = response["choices"][0]["message"]["content"].to_string();
+    let mut usage: Option<TokenUsage> = serde_json::from_value(response["usage"].clone()).ok();
+    // Answers from a cassette cost nothing, so they don't count against the budget
+    if let (false, Some(usage)) = (replayed, usage.as_mut()) {
+        record_usage(&payload.model, usage, image_tokens);
+    }
+
+    Ok((content, usage))
+}
+
+async fn post_to_openai(request: &Value) -> Result<Value, String> {
     let client = Client::new();
 
     let openai_api_key = env::var("OPENAI_API_KEY")
         .map_err(|_| "OPENAI_API_KEY not found in environment".to_string())?;
 
-    let seralized_payload = serde_json::to_string(&payload)
-        .map_err(|e| format!("Failed to serialize payload: {}", e))?;
-
-    let response: Value = client
+    client
         .post(OPENAI_ENDPOINT)
         .header("Content-Type", "application/json")
         .header("Authorization", format!("Bearer {}", openai_api_key))
-        .body(seralized_payload)
+        .body(request.to_string())
         .send()
         .await
         .map_err(|e| format!("Request failed: {}", e))?
         .json()
         .await
-        .map_err(|e| format!("Failed to parse JSON: {}", e))?;
-
-    let content = response["choices"][0]["message"]["content"].to_string();
-
-    Ok(content)
+        .map_err(|e| format!("Failed to parse JSON: {}", e))
 }
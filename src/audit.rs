@@ -0,0 +1,326 @@
+use crate::{
+    cassette::sha256_hex,
+    operations::click_position,
+    recorder::unix_millis,
+    types::{AuditEntry, AuditHead, AuditRecord, InputTarget, Region},
+    window::{active_window, list_windows, window_at},
+};
+use serde_json::Value;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// `prev_hash` of the first entry.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An executed input event, as given to `AuditLog::record`.
+pub struct AuditEvent<'a> {
+    pub action: &'a str,
+    pub detail: &'a str,
+    pub target: InputTarget,
+    /// The screenshot the action was chosen on, the recorded one for replays
+    pub screenshot_path: &'a str,
+    /// The model response the action came from, `None` for replays
+    pub response: Option<&'a str>,
+    pub result: &'a str,
+}
+
+/// An append-only log of executed input events. Every entry holds the hash of the previous
+/// one, so changing or removing an entry breaks the chain. The number of entries and the last
+/// hash are also kept in a `.head` file next to the log to detect truncation.
+pub struct AuditLog {
+    file: File,
+    head_path: PathBuf,
+    head: AuditHead,
+}
+
+impl AuditLog {
+    /// Opens a log to append to it, creating it if needed. An existing log is verified first
+    /// so that a tampered chain is never extended.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let head = if path.exists() {
+            verify_audit_log(path, None)?
+        } else {
+            AuditHead {
+                entries: 0,
+                last_hash: GENESIS_HASH.to_string(),
+            }
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open audit log {}: {}", path.display(), e))?;
+        Ok(AuditLog {
+            file,
+            head_path: head_path(path),
+            head,
+        })
+    }
+
+    /// The hash of the last entry. Keeping it outside of the machine also detects a log that
+    /// was truncated together with its head file.
+    pub fn last_hash(&self) -> &str {
+        &self.head.last_hash
+    }
+
+    pub fn record(&mut self, event: &AuditEvent) -> Result<(), String> {
+        let screenshot_sha256 = fs::read(event.screenshot_path)
+            .map(|screenshot| sha256_hex(&screenshot))
+            .map_err(|e| format!("Failed to read {}: {}", event.screenshot_path, e))?;
+        let record = AuditRecord {
+            seq: self.head.entries + 1,
+            timestamp_ms: unix_millis(),
+            action: event.action.to_string(),
+            detail: event.detail.to_string(),
+            position: event.target.position,
+            window: event.target.window.clone(),
+            screenshot_sha256,
+            response_sha256: event
+                .response
+                .map(|response| sha256_hex(response.as_bytes())),
+            result: event.result.to_string(),
+            prev_hash: self.head.last_hash.clone(),
+        };
+        let hash = entry_hash(&record)?;
+        let line = serde_json::to_string(&AuditEntry {
+            record,
+            hash: hash.clone(),
+        })
+        .map_err(|e| format!("Failed to serialize the audit entry: {}", e))?;
+
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| format!("Failed to write the audit log: {}", e))?;
+        self.head = AuditHead {
+            entries: self.head.entries + 1,
+            last_hash: hash,
+        };
+        write_head(&self.head_path, &self.head)
+    }
+}
+
+/// Where an input event goes: the click position in screen coordinates and the window that
+/// receives it. Looked up before the action, since a click can change the focus.
+pub fn input_target(click: Option<&Value>, region: Option<&Region>) -> InputTarget {
+    let position = click
+        .and_then(|click| click_position(click["x"].as_str()?, click["y"].as_str()?, region).ok());
+    let windows = list_windows().unwrap_or_default();
+    let focused = active_window()
+        .ok()
+        .flatten()
+        .and_then(|id| windows.iter().find(|window| window.id == id));
+    let window = match position {
+        Some((x, y)) => focused
+            .filter(|window| window.region.contains(x, y))
+            .or_else(|| window_at(&windows, x, y)),
+        None => focused,
+    };
+    InputTarget {
+        position,
+        window: window.map(|window| window.title.clone()),
+    }
+}
+
+/// Checks the chain of an audit log and its head file, returning the head. `expected_hash` is
+/// a last hash kept elsewhere, e.g. printed at the end of a run.
+pub fn verify_audit_log(path: &Path, expected_hash: Option<&str>) -> Result<AuditHead, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read audit log {}: {}", path.display(), e))?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        return Err("The last entry is incomplete, the log was truncated".to_string());
+    }
+
+    let mut head = AuditHead {
+        entries: 0,
+        last_hash: GENESIS_HASH.to_string(),
+    };
+    for (i, line) in content.lines().enumerate() {
+        let number = i as u64 + 1;
+        let entry: AuditEntry = serde_json::from_str(line)
+            .map_err(|e| format!("Entry {} is not a valid audit entry: {}", number, e))?;
+        if entry.record.seq != number {
+            return Err(format!(
+                "Entry {} has sequence number {}, entries were removed or reordered",
+                number, entry.record.seq
+            ));
+        }
+        if entry.record.prev_hash != head.last_hash {
+            return Err(format!(
+                "Entry {} does not follow entry {}, the chain is broken",
+                number,
+                number - 1
+            ));
+        }
+        if entry_hash(&entry.record)? != entry.hash {
+            return Err(format!("Entry {} was modified", number));
+        }
+        head = AuditHead {
+            entries: number,
+            last_hash: entry.hash,
+        };
+    }
+
+    let head_path = head_path(path);
+    let recorded_head: AuditHead = fs::read_to_string(&head_path)
+        .map_err(|e| format!("Failed to read {}: {}", head_path.display(), e))
+        .and_then(|content| {
+            serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid head file {}: {}", head_path.display(), e))
+        })?;
+    if recorded_head.entries != head.entries || recorded_head.last_hash != head.last_hash {
+        return Err(format!(
+            "The log has {} entries but its head file expects {}, the log was truncated or modified",
+            head.entries, recorded_head.entries
+        ));
+    }
+    if let Some(expected_hash) = expected_hash {
+        if expected_hash != head.last_hash {
+            return Err(format!(
+                "The last hash is {}, expected {}",
+                head.last_hash, expected_hash
+            ));
+        }
+    }
+
+    Ok(head)
+}
+
+fn entry_hash(record: &AuditRecord) -> Result<String, String> {
+    let record = serde_json::to_string(record)
+        .map_err(|e| format!("Failed to serialize the audit entry: {}", e))?;
+    Ok(sha256_hex(record.as_bytes()))
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head_path = path.as_os_str().to_owned();
+    head_path.push(".head");
+    PathBuf::from(head_path)
+}
+
+/// Replaces the head file at once, so a crash can't leave half of it.
+fn write_head(path: &Path, head: &AuditHead) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(head)
+        .map_err(|e| format!("Failed to serialize the audit head: {}", e))?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)
+        .and_then(|_| fs::rename(&temporary, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log of three entries in a fresh directory.
+    fn write_log(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("auto-pilot-audit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let screenshot = dir.join("screenshot.png");
+        fs::write(&screenshot, b"screenshot").unwrap();
+
+        let path = dir.join("audit.jsonl");
+        let mut audit_log = AuditLog::open(&path).unwrap();
+        for detail in ["first", "second", "third"] {
+            audit_log
+                .record(&AuditEvent {
+                    action: "TYPE",
+                    detail,
+                    target: InputTarget::default(),
+                    screenshot_path: screenshot.to_str().unwrap(),
+                    response: Some("response"),
+                    result: "Type",
+                })
+                .unwrap();
+        }
+        path
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn write_lines(path: &Path, lines: &[String]) {
+        fs::write(
+            path,
+            lines
+                .iter()
+                .map(|line| format!("{}\n", line))
+                .collect::<String>(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn accepts_an_intact_log() {
+        let path = write_log("intact");
+        let last_hash = AuditLog::open(&path).unwrap().last_hash().to_string();
+        let head = verify_audit_log(&path, Some(&last_hash)).unwrap();
+        assert_eq!(head.entries, 3);
+        assert!(verify_audit_log(&path, Some(GENESIS_HASH)).is_err());
+    }
+
+    #[test]
+    fn detects_a_modified_entry() {
+        let path = write_log("modified");
+        let mut lines = lines(&path);
+        lines[1] = lines[1].replace("\"second\"", "\"changed\"");
+        write_lines(&path, &lines);
+        assert_eq!(
+            verify_audit_log(&path, None).unwrap_err(),
+            "Entry 2 was modified"
+        );
+    }
+
+    #[test]
+    fn detects_reordered_entries() {
+        let path = write_log("reordered");
+        let mut lines = lines(&path);
+        lines.swap(1, 2);
+        write_lines(&path, &lines);
+        assert!(verify_audit_log(&path, None)
+            .unwrap_err()
+            .contains("removed or reordered"));
+    }
+
+    #[test]
+    fn detects_a_truncated_tail() {
+        let path = write_log("truncated");
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 10]).unwrap();
+        assert!(verify_audit_log(&path, None)
+            .unwrap_err()
+            .contains("incomplete"));
+
+        // Removing whole entries is caught by the head file
+        write_lines(&path, &lines(&path)[..1]);
+        assert!(verify_audit_log(&path, None)
+            .unwrap_err()
+            .contains("head file expects 3"));
+    }
+
+    #[test]
+    fn detects_a_head_file_mismatch() {
+        let path = write_log("head");
+        write_head(
+            &head_path(&path),
+            &AuditHead {
+                entries: 3,
+                last_hash: GENESIS_HASH.to_string(),
+            },
+        )
+        .unwrap();
+        assert!(verify_audit_log(&path, None)
+            .unwrap_err()
+            .contains("truncated or modified"));
+    }
+}
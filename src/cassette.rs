@@ -0,0 +1,403 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+/// Stands in for the image data of a request when images are ignored.
+const IGNORED_IMAGE: &str = "<image>";
+
+/// Parts of the prompt that describe the live screen, with what stands in for them when
+/// images are ignored.
+const SCREEN_STATE_PATTERNS: [(&str, &str); 4] = [
+    (
+        r#"The mouse pointer is currently at \{ "x": "\d+%", "y": "\d+%" \}"#,
+        "The mouse pointer is currently at <pointer>",
+    ),
+    (
+        r"still changing \d+ seconds",
+        "still changing <seconds> seconds",
+    ),
+    (r"(?m)^Open windows: .*$", "Open windows: <windows>"),
+    (
+        r#"(?m)^The focused window is ".*"\.$"#,
+        "The focused window is <window>.",
+    ),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Send requests to the model and save every exchange
+    Record,
+    /// Answer requests from the saved exchanges without calling the model
+    Replay,
+}
+
+/// One line of a cassette file.
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    /// The request with images replaced by their hash, to keep cassettes small
+    request: Value,
+    /// The raw response body of the API
+    response: Value,
+}
+
+/// Saved model exchanges, matched by a hash of the normalized request. Identical requests
+/// are answered in the order they were recorded.
+struct Cassette {
+    path: PathBuf,
+    mode: CassetteMode,
+    ignore_images: bool,
+    responses: HashMap<String, Vec<Value>>,
+    /// How many responses of each request hash were served
+    served: HashMap<String, usize>,
+}
+
+static CASSETTE: OnceLock<Mutex<Cassette>> = OnceLock::new();
+
+/// Routes all model requests of this process through a cassette. Recording starts a new
+/// cassette file, replaying loads an existing one.
+pub fn install_cassette(
+    path: &Path,
+    mode: CassetteMode,
+    ignore_images: bool,
+) -> Result<(), String> {
+    let mut responses: HashMap<String, Vec<Value>> = HashMap::new();
+    match mode {
+        CassetteMode::Record => {
+            // Never lose a recording by starting a new one in its place
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map_err(|e| match e.kind() {
+                    ErrorKind::AlreadyExists => format!(
+                        "Cassette {} already exists, delete it or record to another file",
+                        path.display()
+                    ),
+                    _ => format!("Failed to create cassette {}: {}", path.display(), e),
+                })?;
+        }
+        CassetteMode::Replay => {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read cassette {}: {}", path.display(), e))?;
+            for (i, line) in content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .enumerate()
+            {
+                let interaction: Interaction = serde_json::from_str(line).map_err(|e| {
+                    format!("Invalid interaction {} in {}: {}", i + 1, path.display(), e)
+                })?;
+                responses
+                    .entry(request_hash(&interaction.request, ignore_images))
+                    .or_default()
+                    .push(interaction.response);
+            }
+        }
+    }
+
+    let cassette = Cassette {
+        path: path.to_path_buf(),
+        mode,
+        ignore_images,
+        responses,
+        served: HashMap::new(),
+    };
+    CASSETTE
+        .set(Mutex::new(cassette))
+        .map_err(|_| "A cassette is already installed".to_string())
+}
+
+/// The recorded response to `request` when replaying a cassette, `None` when the request
+/// should go to the model. Fails when replaying and nothing was recorded for the request.
+pub fn replay_response(request: &Value) -> Result<Option<Value>, String> {
+    let mut cassette = match CASSETTE.get() {
+        Some(cassette) => cassette.lock().map_err(|e| e.to_string())?,
+        None => return Ok(None),
+    };
+    if cassette.mode != CassetteMode::Replay {
+        return Ok(None);
+    }
+
+    let hash = request_hash(&normalize_request(request), cassette.ignore_images);
+    let served = cassette.served.get(&hash).copied().unwrap_or_default();
+    let response = cassette
+        .responses
+        .get(&hash)
+        .and_then(|responses| responses.get(served))
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "No recorded response in cassette {} for request {} (served {} before), the prompt or screenshot differs from the recording",
+                cassette.path.display(),
+                &hash[..12],
+                served
+            )
+        })?;
+    cassette.served.insert(hash, served + 1);
+
+    Ok(Some(response))
+}
+
+/// Saves an exchange when recording a cassette.
+pub fn record_response(request: &Value, response: &Value) -> Result<(), String> {
+    let cassette = match CASSETTE.get() {
+        Some(cassette) => cassette.lock().map_err(|e| e.to_string())?,
+        None => return Ok(()),
+    };
+    if cassette.mode != CassetteMode::Record {
+        return Ok(());
+    }
+
+    let interaction = Interaction {
+        request: normalize_request(request),
+        response: response.clone(),
+    };
+    let line = serde_json::to_string(&interaction)
+        .map_err(|e| format!("Failed to serialize the interaction: {}", e))?;
+    OpenOptions::new()
+        .append(true)
+        .open(&cassette.path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| {
+            format!(
+                "Failed to write cassette {}: {}",
+                cassette.path.display(),
+                e
+            )
+        })
+}
+
+/// Replaces every image data URL with the hash of its content.
+fn normalize_request(request: &Value) -> Value {
+    map_strings(request, &|text| {
+        if text.starts_with("data:image/") {
+            format!("sha256:{}", sha256_hex(text.as_bytes()))
+        } else {
+            text.to_string()
+        }
+    })
+}
+
+/// Hash of a normalized request. Object keys are sorted by serde_json, so the hash does not
+/// depend on field order. Ignoring images also ignores the description of the live screen in
+/// the prompt, like the pointer position and the open windows.
+fn request_hash(normalized_request: &Value, ignore_images: bool) -> String {
+    let request = if ignore_images {
+        let screen_state: Vec<(Regex, &str)> = SCREEN_STATE_PATTERNS
+            .iter()
+            .map(|(pattern, replacement)| (Regex::new(pattern).unwrap(), *replacement))
+            .collect();
+        map_strings(normalized_request, &|text| {
+            if text.starts_with("sha256:") {
+                return IGNORED_IMAGE.to_string();
+            }
+            screen_state
+                .iter()
+                .fold(text.to_string(), |text, (pattern, replacement)| {
+                    pattern.replace_all(&text, *replacement).into_owned()
+                })
+        })
+    } else {
+        normalized_request.clone()
+    };
+    sha256_hex(request.to_string().as_bytes())
+}
+
+fn map_strings(value: &Value, f: &dyn Fn(&str) -> String) -> Value {
+    match value {
+        Value::String(text) => Value::String(f(text)),
+        Value::Array(items) => {
+            Value::Array(items.iter().map(|item| map_strings(item, f)).collect())
+        }
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, field)| (key.clone(), map_strings(field, f)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parsers::format_vision_prompt,
+        secrets::Secrets,
+        types::{
+            Annotation, Config, GridOptions, GridStyle, Observation, PreviousStep, Region,
+            ScreenChange, StabilityOptions, WindowInfo,
+        },
+    };
+    use serde_json::json;
+
+    fn config() -> Config {
+        Config {
+            grid: GridOptions {
+                interval: 100,
+                auto_interval: false,
+                style: GridStyle::Labels,
+                color: [0, 0, 255, 255],
+                thickness: 1,
+            },
+            annotation: Annotation::Grid,
+            refine: false,
+            zoom_factor: 3,
+            zoom_crop_size: 300,
+            stability: StabilityOptions {
+                threshold: 0.002,
+                frames: 2,
+                poll_interval_ms: 250,
+                timeout_ms: 10_000,
+            },
+            region: None,
+            confirm: false,
+            dry_run: false,
+            policy: None,
+            pause_on_takeover: false,
+            resume_after_idle_ms: None,
+            secrets: Secrets::default(),
+            redactor: None,
+            confirm_risk: None,
+            risk_ocr: false,
+        }
+    }
+
+    fn window(id: u32, title: &str) -> WindowInfo {
+        WindowInfo {
+            id,
+            title: title.to_string(),
+            region: Region {
+                x: 0,
+                y: 0,
+                width: 100,
+                height: 100,
+            },
+        }
+    }
+
+    /// A normalized request with the prompt of a screen in the given state.
+    fn request(
+        objective: &str,
+        pointer: (u32, u32),
+        windows: &[&str],
+        waited_ms: u64,
+        image: &str,
+    ) -> Value {
+        let observation = Observation {
+            screenshot_path: String::new(),
+            annotated_path: String::new(),
+            width: 1000,
+            height: 500,
+            marks: Vec::new(),
+            grid: None,
+            pointer: Some(pointer),
+            previous_step: Some(PreviousStep {
+                action: None,
+                screen_change: Some(ScreenChange {
+                    changed: true,
+                    stable: false,
+                    waited_ms,
+                }),
+                feedback: None,
+            }),
+            region: None,
+            windows: windows
+                .iter()
+                .enumerate()
+                .map(|(id, title)| window(id as u32, title))
+                .collect(),
+            focused_window: Some(0),
+            redactions: Vec::new(),
+        };
+        let prompt = format_vision_prompt(objective, "", &config(), &observation);
+        normalize_request(&json!({
+            "model": "gpt-4-vision-preview",
+            "messages": [{
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": prompt},
+                    {"type": "image_url", "image_url": {"url": format!("data:image/jpeg;base64,{}", image)}},
+                ],
+            }],
+        }))
+    }
+
+    #[test]
+    fn replaces_images_with_their_digest() {
+        let request = request("Open the mail", (10, 10), &[], 1000, "AAAA");
+        let url = request["messages"][0]["content"][1]["image_url"]["url"]
+            .as_str()
+            .unwrap();
+        assert_eq!(
+            url,
+            format!("sha256:{}", sha256_hex(b"data:image/jpeg;base64,AAAA"))
+        );
+    }
+
+    #[test]
+    fn compares_the_screen_unless_images_are_ignored() {
+        let recorded = request("Open the mail", (10, 10), &["Mail"], 1000, "AAAA");
+        let changed = [
+            request("Open the mail", (10, 10), &["Mail"], 1000, "BBBB"),
+            request("Open the mail", (500, 250), &["Mail"], 1000, "AAAA"),
+            request(
+                "Open the mail",
+                (10, 10),
+                &["Mail", "Terminal"],
+                1000,
+                "AAAA",
+            ),
+            request(
+                "Open the mail",
+                (10, 10),
+                &["Terminal", "Mail"],
+                1000,
+                "AAAA",
+            ),
+            request("Open the mail", (10, 10), &["Mail"], 4000, "AAAA"),
+        ];
+        for replayed in &changed {
+            assert_ne!(
+                request_hash(&recorded, false),
+                request_hash(replayed, false)
+            );
+            assert_eq!(request_hash(&recorded, true), request_hash(replayed, true));
+        }
+    }
+
+    #[test]
+    fn compares_the_rest_of_the_prompt_when_images_are_ignored() {
+        let recorded = request("Open the mail", (10, 10), &["Mail"], 1000, "AAAA");
+        let replayed = request("Open the calendar", (10, 10), &["Mail"], 1000, "AAAA");
+        assert_ne!(request_hash(&recorded, true), request_hash(&replayed, true));
+    }
+
+    #[test]
+    fn refuses_to_record_over_a_cassette() {
+        let path =
+            std::env::temp_dir().join(format!("auto-pilot-cassette-{}.jsonl", std::process::id()));
+        fs::write(&path, "recorded\n").unwrap();
+        let installed = install_cassette(&path, CassetteMode::Record, false);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(installed.unwrap_err().contains("already exists"));
+        assert_eq!(content, "recorded\n");
+    }
+}
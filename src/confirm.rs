@@ -0,0 +1,159 @@
+use crate::{
+    parsers::{
+        convert_string_to_json, parse_action_text, parse_openai_response, resolve_click_detail,
+        ActionType,
+    },
+    screen::add_previous_action_to_image,
+    terminal::{discard_pending_input, read_line},
+    types::{Observation, PreviousAction, RiskAssessment, RiskLevel},
+    window::{active_window, focus_window},
+};
+use colored::Colorize;
+use serde_json::Value;
+use std::io::{self, Write};
+
+pub enum ConfirmChoice {
+    Approve,
+    Skip,
+    /// `click` is the resolved position of an edited CLICK
+    Edit {
+        action: String,
+        detail: String,
+        click: Option<Value>,
+    },
+    Abort,
+}
+
+/// Shows the action the model chose and asks the user what to do with it. `preview` is
+/// drawn on the screenshot so the user can see where a click or typing will land. High risk
+/// actions are only approved by typing yes. The window that had the focus gets it back
+/// afterwards, so the action doesn't land in the terminal the user answered in.
+pub async fn confirm_action(
+    action: &str,
+    detail: &str,
+    preview: Option<&PreviousAction>,
+    risk: Option<&RiskAssessment>,
+    observation: &Observation,
+) -> Result<ConfirmChoice, String> {
+    let preview_path = match preview {
+        Some(preview) => {
+            let preview_path = "screenshots/screenshot_preview.png";
+            add_previous_action_to_image(&observation.screenshot_path, preview_path, preview)
+                .map_err(|e| format!("Error drawing the action preview: {}", e))?;
+            Some(preview_path)
+        }
+        None => None,
+    };
+
+    println!(
+        "{} {} {} {}",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Confirm]".bright_magenta(),
+        action,
+        detail
+    );
+    if let Some(preview_path) = preview_path {
+        println!(
+            "The target is marked in green or yellow on {}",
+            preview_path
+        );
+    }
+
+    let high_risk = match risk {
+        Some(risk) if risk.level > RiskLevel::Low => {
+            println!(
+                "{} {:?} risk: {}",
+                "Warning:".red(),
+                risk.level,
+                risk.reasons.join(", ")
+            );
+            risk.level == RiskLevel::High
+        }
+        _ => false,
+    };
+    let question = if high_risk {
+        "Type yes to run it, s to skip it, e to edit it or a to abort"
+    } else {
+        "Press Enter to run it, s to skip it, e to edit it or a to abort"
+    };
+
+    let focused = active_window().ok().flatten();
+    let choice = ask_choice(question, high_risk, observation).await;
+    if let Some(id) = focused {
+        if let Err(e) = focus_window(id) {
+            eprintln!("Failed to give the focus back to the window: {}", e);
+        }
+    }
+    choice
+}
+
+async fn ask_choice(
+    question: &str,
+    high_risk: bool,
+    observation: &Observation,
+) -> Result<ConfirmChoice, String> {
+    loop {
+        let answer = ask(question).await?;
+        match answer.as_str() {
+            "yes" => return Ok(ConfirmChoice::Approve),
+            "" | "y" if !high_risk => return Ok(ConfirmChoice::Approve),
+            "s" => return Ok(ConfirmChoice::Skip),
+            "a" => return Ok(ConfirmChoice::Abort),
+            "e" => {
+                let edited = ask(
+                    r#"Enter the action to run instead, e.g. TYPE hello, CLICK 17, CLICK C4 or CLICK {"x": "50%", "y": "20%"}"#,
+                )
+                .await?;
+                // A mistake in the edit is asked again instead of ending the run
+                match parse_edit(&edited, observation) {
+                    Ok((action, detail, click)) => {
+                        return Ok(ConfirmChoice::Edit {
+                            action,
+                            detail,
+                            click,
+                        })
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses an edited action. Clicks on marks and cells are resolved like the model's clicks.
+fn parse_edit(
+    edited: &str,
+    observation: &Observation,
+) -> Result<(String, String, Option<Value>), String> {
+    let (action, detail) = parse_action_text(edited).or_else(|e| {
+        // `CLICK C4` is not JSON, but it is how the model clicks on cells
+        match parse_openai_response(edited.trim()) {
+            Ok((action, detail)) if action == "CLICK" => Ok((action, detail)),
+            _ => Err(e),
+        }
+    })?;
+    let click = match ActionType::from_str(&action) {
+        ActionType::Click => {
+            let click_detail = convert_string_to_json(&detail)
+                .map_err(|e| format!("Invalid click {}: {}", detail, e))?;
+            Some(resolve_click_detail(click_detail, observation)?)
+        }
+        _ => None,
+    };
+    Ok((action, detail, click))
+}
+
+async fn ask(question: &str) -> Result<String, String> {
+    // Keys the agent typed into the terminal must not answer for the user
+    discard_pending_input();
+    print!("{} ", question);
+    io::stdout()
+        .flush()
+        .map_err(|e| format!("Failed to write the question: {}", e))?;
+    // Without this a closed stdin would approve every action
+    let answer = read_line()
+        .await?
+        .ok_or("No answer, stdin is closed".to_string())?;
+    Ok(answer.trim().to_string())
+}
@@ -1,3 +1,118 @@
 pub const DEBUG: bool = false;
 pub const OPENAI_ENDPOINT: &str = "https://api.openai.com/v1/chat/completions";
 pub const MAX_TOKENS: i32 = 1000;
+
+// Images sent to the vision model are scaled to fit in MAX_SIDE x MAX_SIDE and then
+// so that their short side is at most SHORT_SIDE
+pub const MODEL_IMAGE_MAX_SIDE: u32 = 2048;
+pub const MODEL_IMAGE_SHORT_SIDE: u32 = 768;
+
+// Grid labels, sizes are in pixels of the image the model looks at
+pub const LABEL_MODEL_FONT_SIZE: u32 = 11;
+pub const LABEL_MIN_FONT_SIZE: u32 = 10;
+pub const AUTO_GRID_MODEL_INTERVAL: u32 = 96;
+
+// Set-of-marks element detection
+pub const MARK_CANNY_LOW: f32 = 40.0;
+pub const MARK_CANNY_HIGH: f32 = 100.0;
+pub const MARK_DILATE_RADIUS: u8 = 3;
+pub const MARK_MIN_SIZE: u32 = 12;
+pub const MARK_MAX_COUNT: usize = 200;
+
+// Zoom-in click refinement
+pub const REFINE_GRID_INTERVAL: i32 = 150;
+
+// Screen change detection, frames are compared at 1/STABILITY_DOWNSCALE of their size
+pub const STABILITY_DOWNSCALE: u32 = 4;
+pub const STABILITY_PIXEL_TOLERANCE: u8 = 24;
+pub const STABILITY_POLL_INTERVAL_MS: u64 = 250;
+
+// Virtual displays
+pub const VIRTUAL_DISPLAY_FIRST_NUMBER: u32 = 99;
+pub const VIRTUAL_DISPLAY_START_TIMEOUT_MS: u64 = 5000;
+
+// Run recording
+pub const RUNS_DIR: &str = "runs";
+pub const TRAJECTORY_SCHEMA_VERSION: u32 = 1;
+
+// Emergency stop, the corner zone is in pixels from the corner of the screen
+pub const EMERGENCY_POLL_INTERVAL_MS: u64 = 50;
+pub const FAIL_SAFE_CORNER_SIZE: i32 = 2;
+
+// Human takeover detection, the tolerance is in pixels
+pub const TAKEOVER_POINTER_TOLERANCE: i32 = 2;
+pub const TAKEOVER_POLL_INTERVAL_MS: u64 = 250;
+
+// Secrets
+pub const SECRET_ENV_PREFIX: &str = "AUTO_PILOT_SECRET_";
+pub const VAULT_FILE: &str = "secrets.vault";
+pub const VAULT_PASSPHRASE_ENV: &str = "AUTO_PILOT_VAULT_PASSPHRASE";
+pub const VAULT_PBKDF2_ITERATIONS: u32 = 600_000;
+pub const VAULT_VERSION: u32 = 1;
+
+// Screenshot redaction
+pub const REDACT_BLUR_SIGMA: f32 = 16.0;
+
+// Risk assessment, words are matched case-insensitively as whole words
+pub const HIGH_RISK_WORDS: [&str; 22] = [
+    "delete",
+    "remove",
+    "erase",
+    "destroy",
+    "discard",
+    "wipe",
+    "format",
+    "reset",
+    "uninstall",
+    "send",
+    "submit",
+    "publish",
+    "pay",
+    "purchase",
+    "buy",
+    "checkout",
+    "order",
+    "transfer",
+    "withdraw",
+    "empty trash",
+    "deactivate",
+    "terminate",
+];
+pub const MEDIUM_RISK_WORDS: [&str; 14] = [
+    "save",
+    "close",
+    "overwrite",
+    "replace",
+    "share",
+    "accept",
+    "confirm",
+    "post",
+    "move",
+    "rename",
+    "install",
+    "sign out",
+    "log out",
+    "unsubscribe",
+];
+pub const HIGH_RISK_TYPE_PATTERNS: [&str; 5] = [
+    r"\brm\s+-[a-z]*[rf]",
+    r"\bsudo\b",
+    r"(?i)\bdrop\s+(table|database)\b",
+    r"(?i)\bdelete\s+from\b",
+    r"\b(shutdown|reboot|mkfs|dd\s+if=)",
+];
+pub const RISK_OCR_CROP_SIZE: u32 = 240;
+pub const RISK_OCR_ZOOM: u32 = 2;
+// Distance in pixels of the screenshot from the click to the text that is read
+pub const RISK_OCR_RADIUS: u32 = 40;
+
+// Token and cost accounting. Images cost a base amount plus an amount per tile of the image
+// the model looks at. Prices are in USD per million prompt and completion tokens
+pub const IMAGE_BASE_TOKENS: u64 = 85;
+pub const IMAGE_TILE_TOKENS: u64 = 170;
+pub const IMAGE_TILE_SIZE: u32 = 512;
+pub const MODEL_PRICES: [(&str, f64, f64); 3] = [
+    ("gpt-4-vision-preview", 10.0, 30.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4o", 5.0, 15.0),
+];
@@ -0,0 +1,322 @@
+use crate::{
+    constants::{IMAGE_BASE_TOKENS, IMAGE_TILE_SIZE, IMAGE_TILE_TOKENS, MODEL_PRICES},
+    screen::model_image_scale,
+    types::{Budget, ImageMessageContent, Message, ModelPrice, TokenUsage},
+};
+use base64::{engine::general_purpose, Engine as _};
+use colored::Colorize;
+use image::io::Reader;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::Cursor,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+/// The usage of all model calls of the run and what they may use.
+struct CostTracker {
+    prices: HashMap<String, ModelPrice>,
+    budget: Budget,
+    calls: u32,
+    /// `cost_usd` is the cost of the calls to priced models
+    usage: TokenUsage,
+    unpriced_models: BTreeSet<String>,
+}
+
+static COST_TRACKER: OnceLock<Mutex<CostTracker>> = OnceLock::new();
+
+/// The built-in prices, overridden and extended by a JSON price table mapping model names to
+/// USD per million tokens, e.g. `{"gpt-4o": {"prompt": 5.0, "completion": 15.0}}`.
+pub fn load_prices(price_table: Option<&Path>) -> Result<HashMap<String, ModelPrice>, String> {
+    let mut prices: HashMap<String, ModelPrice> = MODEL_PRICES
+        .iter()
+        .map(|(model, prompt, completion)| {
+            (
+                model.to_string(),
+                ModelPrice {
+                    prompt: *prompt,
+                    completion: *completion,
+                },
+            )
+        })
+        .collect();
+    if let Some(path) = price_table {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read price table {}: {}", path.display(), e))?;
+        let table: HashMap<String, ModelPrice> = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid price table {}: {}", path.display(), e))?;
+        prices.extend(table);
+    }
+    Ok(prices)
+}
+
+/// Accounts the usage of every model call of this process and checks it against `budget`.
+pub fn install_cost_tracker(
+    prices: HashMap<String, ModelPrice>,
+    budget: Budget,
+) -> Result<(), String> {
+    COST_TRACKER
+        .set(Mutex::new(CostTracker::new(prices, budget)))
+        .map_err(|_| "A cost tracker is already installed".to_string())
+}
+
+impl CostTracker {
+    fn new(prices: HashMap<String, ModelPrice>, budget: Budget) -> Self {
+        CostTracker {
+            prices,
+            budget,
+            calls: 0,
+            usage: TokenUsage::default(),
+            unpriced_models: BTreeSet::new(),
+        }
+    }
+
+    fn record(&mut self, model: &str, usage: &mut TokenUsage) {
+        usage.cost_usd = self.prices.get(model).map(|price| {
+            (usage.prompt_tokens as f64 * price.prompt
+                + usage.completion_tokens as f64 * price.completion)
+                / 1_000_000.0
+        });
+        if usage.cost_usd.is_none() {
+            self.unpriced_models.insert(model.to_string());
+        }
+
+        self.calls += 1;
+        self.usage = TokenUsage {
+            prompt_tokens: self.usage.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: self.usage.completion_tokens + usage.completion_tokens,
+            total_tokens: self.usage.total_tokens + usage.total_tokens,
+            image_tokens: self.usage.image_tokens + usage.image_tokens,
+            cost_usd: Some(self.usage.cost_usd.unwrap_or(0.0) + usage.cost_usd.unwrap_or(0.0)),
+        };
+    }
+
+    fn budget_exceeded(&self) -> Option<String> {
+        if let Some(max_tokens) = self.budget.max_tokens {
+            if self.usage.total_tokens >= max_tokens {
+                return Some(format!(
+                    "the run used {} tokens of the {} allowed",
+                    self.usage.total_tokens, max_tokens
+                ));
+            }
+        }
+        if let Some(max_cost_usd) = self.budget.max_cost_usd {
+            if let Some(model) = self.unpriced_models.iter().next() {
+                return Some(format!(
+                    "{} has no price, so the cost can't be limited. Add it with --price-table",
+                    model
+                ));
+            }
+            let cost_usd = self.usage.cost_usd.unwrap_or(0.0);
+            if cost_usd >= max_cost_usd {
+                return Some(format!(
+                    "the run cost ${:.4} of the ${:.2} allowed",
+                    cost_usd, max_cost_usd
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Completes the usage reported for a call with the estimated image tokens and cost, and adds
+/// it to the run.
+pub fn record_usage(model: &str, usage: &mut TokenUsage, image_tokens: u64) {
+    usage.image_tokens = image_tokens;
+    if let Some(tracker) = COST_TRACKER.get() {
+        tracker.lock().unwrap().record(model, usage);
+    }
+}
+
+/// Why the run must stop, once it used up its budget. The budget is checked between calls,
+/// so the last call can go over it.
+pub fn budget_exceeded() -> Option<String> {
+    COST_TRACKER.get()?.lock().unwrap().budget_exceeded()
+}
+
+/// Prints the tokens and estimated cost of the run so far.
+pub fn print_cost_summary() {
+    let tracker = match COST_TRACKER.get() {
+        Some(tracker) => tracker.lock().unwrap(),
+        None => return,
+    };
+    if tracker.calls == 0 {
+        return;
+    }
+
+    let usage = tracker.usage;
+    let mut summary = format!(
+        "{} model calls, {} tokens ({} prompt including {} for images, {} completion), ${:.4}",
+        tracker.calls,
+        usage.total_tokens,
+        usage.prompt_tokens,
+        usage.image_tokens,
+        usage.completion_tokens,
+        usage.cost_usd.unwrap_or(0.0)
+    );
+    if !tracker.unpriced_models.is_empty() {
+        let models: Vec<&str> = tracker.unpriced_models.iter().map(String::as_str).collect();
+        summary.push_str(&format!(
+            " not counting {}, which has no price",
+            models.join(", ")
+        ));
+    }
+    println!(
+        "{} {} {}",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Cost]".bright_magenta(),
+        summary
+    );
+}
+
+/// Tokens the images of a request cost: a base amount plus an amount per tile of the image
+/// the model looks at.
+pub fn estimate_image_tokens(messages: &[Message]) -> u64 {
+    messages
+        .iter()
+        .filter_map(|message| match message {
+            Message::ImageMessage(message) => Some(&message.content),
+            Message::TextMessage(_) => None,
+        })
+        .flatten()
+        .filter_map(|content| match content {
+            ImageMessageContent::ImageUrl { image_url } => image_size(&image_url.url),
+            ImageMessageContent::Text { .. } => None,
+        })
+        .map(|(width, height)| {
+            let scale = model_image_scale(width, height);
+            let tiles = |side: u32| ((side as f32 / scale) / IMAGE_TILE_SIZE as f32).ceil() as u64;
+            IMAGE_BASE_TOKENS + IMAGE_TILE_TOKENS * tiles(width) * tiles(height)
+        })
+        .sum()
+}
+
+/// The size of a base64 data URL image.
+fn image_size(url: &str) -> Option<(u32, u32)> {
+    let (_, data) = url.split_once("base64,")?;
+    let data = general_purpose::STANDARD.decode(data).ok()?;
+    Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ImageMessage, ImageUrl, Role, TextMessage};
+    use image::{ImageOutputFormat, RgbImage};
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            ..TokenUsage::default()
+        }
+    }
+
+    fn tracker(max_tokens: Option<u64>, max_cost_usd: Option<f64>) -> CostTracker {
+        CostTracker::new(
+            load_prices(None).unwrap(),
+            Budget {
+                max_tokens,
+                max_cost_usd,
+            },
+        )
+    }
+
+    fn image_message(width: u32, height: u32) -> Message {
+        let mut png = Vec::new();
+        RgbImage::new(width, height)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+        Message::ImageMessage(ImageMessage {
+            role: Role::User,
+            content: vec![
+                ImageMessageContent::Text {
+                    text: "What is on the screen?".to_string(),
+                },
+                ImageMessageContent::ImageUrl {
+                    image_url: ImageUrl {
+                        url: format!(
+                            "data:image/png;base64,{}",
+                            general_purpose::STANDARD.encode(png)
+                        ),
+                    },
+                },
+            ],
+        })
+    }
+
+    #[test]
+    fn prices_the_usage_of_known_models() {
+        let mut tracker = tracker(None, None);
+        let mut call = usage(1_000_000, 100_000);
+        tracker.record("gpt-4o", &mut call);
+        assert_eq!(call.cost_usd, Some(6.5));
+        tracker.record("gpt-4o", &mut usage(1_000_000, 0));
+        assert_eq!(tracker.calls, 2);
+        assert_eq!(tracker.usage.total_tokens, 2_100_000);
+        assert_eq!(tracker.usage.cost_usd, Some(11.5));
+    }
+
+    #[test]
+    fn stops_at_the_token_budget() {
+        let mut tracker = tracker(Some(1000), None);
+        tracker.record("unpriced", &mut usage(600, 300));
+        assert_eq!(tracker.budget_exceeded(), None);
+        tracker.record("unpriced", &mut usage(50, 50));
+        assert_eq!(
+            tracker.budget_exceeded().unwrap(),
+            "the run used 1000 tokens of the 1000 allowed"
+        );
+    }
+
+    #[test]
+    fn stops_at_the_cost_budget() {
+        let mut tracker = tracker(None, Some(10.0));
+        tracker.record("gpt-4o", &mut usage(1_000_000, 0));
+        assert_eq!(tracker.budget_exceeded(), None);
+        tracker.record("gpt-4o", &mut usage(1_000_000, 0));
+        assert_eq!(
+            tracker.budget_exceeded().unwrap(),
+            "the run cost $10.0000 of the $10.00 allowed"
+        );
+    }
+
+    #[test]
+    fn cannot_limit_the_cost_of_unpriced_models() {
+        let mut tracker = tracker(None, Some(10.0));
+        tracker.record("unpriced", &mut usage(10, 10));
+        assert!(tracker
+            .budget_exceeded()
+            .unwrap()
+            .contains("unpriced has no price"));
+    }
+
+    #[test]
+    fn estimates_the_tokens_of_the_image_the_model_sees() {
+        let text = Message::TextMessage(TextMessage {
+            role: Role::User,
+            content: "Hello".to_string(),
+        });
+        assert_eq!(estimate_image_tokens(&[text]), 0);
+        assert_eq!(estimate_image_tokens(&[image_message(100, 50)]), 85 + 170);
+        assert_eq!(
+            estimate_image_tokens(&[image_message(513, 512)]),
+            85 + 170 * 2
+        );
+        // Shrunk to 1024x768, so 2x2 tiles
+        assert_eq!(
+            estimate_image_tokens(&[image_message(2048, 1536)]),
+            85 + 170 * 4
+        );
+        assert_eq!(
+            estimate_image_tokens(&[image_message(100, 50), image_message(100, 50)]),
+            2 * (85 + 170)
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{
+    constants::{VIRTUAL_DISPLAY_FIRST_NUMBER, VIRTUAL_DISPLAY_START_TIMEOUT_MS},
+    types::{DisplayServer, VirtualDisplayOptions},
+};
+use std::{
+    env,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// An X server started for this run. Screen capture and input go to it through `DISPLAY`
+/// while it is alive, and it is shut down together with its applications when dropped.
+pub struct VirtualDisplay {
+    pub display: String,
+    server: Child,
+    applications: Vec<Child>,
+    previous_display: Option<String>,
+}
+
+impl VirtualDisplay {
+    pub fn start(options: &VirtualDisplayOptions) -> Result<Self, String> {
+        let number = free_display_number()?;
+        let display = format!(":{}", number);
+        let size = format!("{}x{}", options.width, options.height);
+
+        let mut command = match options.server {
+            DisplayServer::Xvfb => {
+                let mut command = Command::new("Xvfb");
+                command.args([&display, "-screen", "0", &format!("{}x24", size)]);
+                command
+            }
+            DisplayServer::Xephyr => {
+                let mut command = Command::new("Xephyr");
+                command.args([&display, "-screen", &size]);
+                command
+            }
+        };
+        let server = command
+            .args(["-nolisten", "tcp"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to start {:?}: {}", options.server, e))?;
+
+        let mut virtual_display = VirtualDisplay {
+            display: display.clone(),
+            server,
+            applications: Vec::new(),
+            previous_display: env::var("DISPLAY").ok(),
+        };
+        virtual_display.wait_until_ready(number)?;
+        env::set_var("DISPLAY", &display);
+
+        for command in options.window_manager.iter().chain(options.startup.iter()) {
+            virtual_display.launch(command)?;
+        }
+
+        Ok(virtual_display)
+    }
+
+    /// Runs a shell command on the virtual display. It is killed with the display.
+    pub fn launch(&mut self, command: &str) -> Result<(), String> {
+        let child = Command::new("sh")
+            .args(["-c", command])
+            .env("DISPLAY", &self.display)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to start {}: {}", command, e))?;
+        self.applications.push(child);
+        Ok(())
+    }
+
+    fn wait_until_ready(&mut self, number: u32) -> Result<(), String> {
+        let socket = format!("/tmp/.X11-unix/X{}", number);
+        let start = Instant::now();
+        while !Path::new(&socket).exists() {
+            if let Ok(Some(status)) = self.server.try_wait() {
+                return Err(format!("The X server exited with {}", status));
+            }
+            if start.elapsed() > Duration::from_millis(VIRTUAL_DISPLAY_START_TIMEOUT_MS) {
+                return Err(format!("The X server did not start on {}", self.display));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        for application in self.applications.iter_mut().rev() {
+            let _ = application.kill();
+            let _ = application.wait();
+        }
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+
+        match &self.previous_display {
+            Some(display) => env::set_var("DISPLAY", display),
+            None => env::remove_var("DISPLAY"),
+        }
+    }
+}
+
+/// First display number without an X server socket or lock file.
+fn free_display_number() -> Result<u32, String> {
+    (VIRTUAL_DISPLAY_FIRST_NUMBER..VIRTUAL_DISPLAY_FIRST_NUMBER + 100)
+        .find(|number| {
+            !Path::new(&format!("/tmp/.X11-unix/X{}", number)).exists()
+                && !Path::new(&format!("/tmp/.X{}-lock", number)).exists()
+        })
+        .ok_or_else(|| "No free X display number".to_string())
+}
@@ -0,0 +1,231 @@
+use crate::{
+    constants::{EMERGENCY_POLL_INTERVAL_MS, FAIL_SAFE_CORNER_SIZE},
+    types::{Hotkey, ScreenCorner},
+};
+use colored::Colorize;
+use enigo::{Enigo, MouseControllable};
+use std::{
+    sync::{Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use x11 as platform;
+
+#[cfg(not(target_os = "linux"))]
+use unsupported as platform;
+
+/// Why the run was stopped, set once by the watcher.
+static ABORT_REASON: OnceLock<String> = OnceLock::new();
+
+/// Where the agent last put the pointer, until the pointer is seen anywhere else.
+static AGENT_POINTER: Mutex<Option<(i32, i32)>> = Mutex::new(None);
+
+/// Stops the run. Only the first reason is kept.
+pub fn request_abort(reason: &str) {
+    if ABORT_REASON.set(reason.to_string()).is_ok() {
+        eprintln!(
+            "{} {} {}",
+            "[Auto-Pilot-Computer]".blue(),
+            "[Emergency stop]".red(),
+            reason
+        );
+    }
+}
+
+pub fn abort_reason() -> Option<&'static str> {
+    ABORT_REASON.get().map(String::as_str)
+}
+
+pub fn is_aborted() -> bool {
+    ABORT_REASON.get().is_some()
+}
+
+/// Resolves once the run was stopped, to cancel a step that is waiting for the model.
+pub async fn wait_for_abort() -> &'static str {
+    loop {
+        if let Some(reason) = abort_reason() {
+            return reason;
+        }
+        tokio::time::sleep(Duration::from_millis(EMERGENCY_POLL_INTERVAL_MS)).await;
+    }
+}
+
+/// Moves the pointer for the agent. The fail-safe corner ignores the pointer while it stays
+/// where the agent put it, so the agent clicking into the corner doesn't stop the run.
+pub fn move_pointer(enigo: &mut Enigo, x: i32, y: i32) {
+    // Held during the move, so the watcher can't see the new position before it is noted
+    let mut agent_pointer = AGENT_POINTER.lock().unwrap();
+    *agent_pointer = Some((x, y));
+    enigo.mouse_move_to(x, y);
+}
+
+/// Watches the keyboard for `hotkey` and the pointer for `corner` on a background thread and
+/// stops the run when either is hit. A hotkey that can't be watched is reported and only the
+/// corner is watched.
+pub fn start_emergency_stop(hotkey: Option<&Hotkey>, corner: Option<ScreenCorner>) {
+    let hotkey_watcher = hotkey.and_then(|hotkey| match platform::HotkeyWatcher::new(hotkey) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            eprintln!("The abort hotkey is disabled: {}", e);
+            None
+        }
+    });
+    if hotkey_watcher.is_none() && corner.is_none() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let enigo = Enigo::new();
+        let (width, height) = enigo.main_display_size();
+        loop {
+            if let Some(watcher) = &hotkey_watcher {
+                match watcher.is_pressed() {
+                    Ok(true) => return request_abort("abort hotkey pressed"),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Failed to read the keyboard: {}", e),
+                }
+            }
+            if let Some(corner) = corner {
+                if user_pointer_in_corner(&enigo, (width, height), corner) {
+                    return request_abort("pointer moved into the fail-safe corner");
+                }
+            }
+            thread::sleep(Duration::from_millis(EMERGENCY_POLL_INTERVAL_MS));
+        }
+    });
+}
+
+/// Whether the user moved the pointer into `corner`. The pointer is the user's again as soon
+/// as it left the position the agent moved it to.
+fn user_pointer_in_corner(enigo: &Enigo, size: (i32, i32), corner: ScreenCorner) -> bool {
+    let mut agent_pointer = AGENT_POINTER.lock().unwrap();
+    let location = enigo.mouse_location();
+    if *agent_pointer == Some(location) {
+        return false;
+    }
+    *agent_pointer = None;
+    in_corner(location, size, corner)
+}
+
+fn in_corner((x, y): (i32, i32), (width, height): (i32, i32), corner: ScreenCorner) -> bool {
+    let near_left = x < FAIL_SAFE_CORNER_SIZE;
+    let near_top = y < FAIL_SAFE_CORNER_SIZE;
+    let near_right = x >= width - FAIL_SAFE_CORNER_SIZE;
+    let near_bottom = y >= height - FAIL_SAFE_CORNER_SIZE;
+    match corner {
+        ScreenCorner::TopLeft => near_top && near_left,
+        ScreenCorner::TopRight => near_top && near_right,
+        ScreenCorner::BottomLeft => near_bottom && near_left,
+        ScreenCorner::BottomRight => near_bottom && near_right,
+    }
+}
+
+/// Hotkey detection by polling the keymap of the X server, so it works no matter which
+/// window has the focus and without grabbing the keys from other applications.
+#[cfg(target_os = "linux")]
+mod x11 {
+    use crate::types::Hotkey;
+    use x11rb::{
+        connection::Connection, protocol::xproto::ConnectionExt as _,
+        rust_connection::RustConnection,
+    };
+
+    pub struct HotkeyWatcher {
+        conn: RustConnection,
+        /// For every key of the hotkey, the keycodes that produce it
+        keycodes: Vec<Vec<u8>>,
+    }
+
+    impl HotkeyWatcher {
+        pub fn new(hotkey: &Hotkey) -> Result<Self, String> {
+            let (conn, _) = x11rb::connect(None)
+                .map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+            let min_keycode = conn.setup().min_keycode;
+            let max_keycode = conn.setup().max_keycode;
+            let mapping = conn
+                .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+                .map_err(|e| e.to_string())?
+                .reply()
+                .map_err(|e| e.to_string())?;
+            let per_keycode = mapping.keysyms_per_keycode.max(1) as usize;
+
+            let keycodes = hotkey
+                .keys
+                .iter()
+                .map(|key| {
+                    let keysyms = keysyms(key);
+                    let keycodes: Vec<u8> = mapping
+                        .keysyms
+                        .chunks(per_keycode)
+                        .zip(min_keycode..=max_keycode)
+                        .filter(|(syms, _)| syms.iter().any(|sym| keysyms.contains(sym)))
+                        .map(|(_, keycode)| keycode)
+                        .collect();
+                    if keycodes.is_empty() {
+                        Err(format!("No key on this keyboard produces \"{}\"", key))
+                    } else {
+                        Ok(keycodes)
+                    }
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+
+            Ok(HotkeyWatcher { conn, keycodes })
+        }
+
+        /// Whether all keys of the hotkey are held down.
+        pub fn is_pressed(&self) -> Result<bool, String> {
+            let keymap = self
+                .conn
+                .query_keymap()
+                .map_err(|e| e.to_string())?
+                .reply()
+                .map_err(|e| e.to_string())?
+                .keys;
+            let is_down = |keycode: &u8| keymap[*keycode as usize / 8] & (1 << (keycode % 8)) != 0;
+            Ok(self
+                .keycodes
+                .iter()
+                .all(|keycodes| keycodes.iter().any(is_down)))
+        }
+    }
+
+    /// X keysyms of a key name accepted by `parse_hotkey`.
+    fn keysyms(key: &str) -> Vec<u32> {
+        match key {
+            "ctrl" => vec![0xffe3, 0xffe4],
+            "shift" => vec![0xffe1, 0xffe2],
+            "alt" => vec![0xffe9, 0xffea],
+            "super" => vec![0xffeb, 0xffec],
+            "escape" => vec![0xff1b],
+            "pause" => vec![0xff13],
+            "space" => vec![0x0020],
+            "enter" => vec![0xff0d],
+            "tab" => vec![0xff09],
+            "backspace" => vec![0xff08],
+            _ => match key.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+                Some(number) => vec![0xffbe + number - 1],
+                // Latin-1 keysyms are the character codes
+                None => key.chars().map(|c| c as u32).collect(),
+            },
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use crate::types::Hotkey;
+
+    pub struct HotkeyWatcher;
+
+    impl HotkeyWatcher {
+        pub fn new(_hotkey: &Hotkey) -> Result<Self, String> {
+            Err("The abort hotkey is only supported on X11".to_string())
+        }
+
+        pub fn is_pressed(&self) -> Result<bool, String> {
+            Ok(false)
+        }
+    }
+}
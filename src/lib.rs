@@ -1,9 +1,28 @@
 pub mod action;
+pub mod audit;
+pub mod cassette;
+pub mod confirm;
 pub mod constants;
+pub mod cost;
+pub mod display;
+pub mod emergency;
 pub mod initialize;
+pub mod ocr;
 pub mod operations;
 pub mod parsers;
+pub mod policy;
 pub mod prompts;
+pub mod recorder;
+pub mod redact;
+pub mod refine;
+pub mod replay;
+pub mod report;
+pub mod risk;
 pub mod screen;
+pub mod secrets;
 pub mod summarize;
+pub mod takeover;
+pub mod terminal;
+pub mod timelapse;
 pub mod types;
+pub mod window;
@@ -1,14 +1,50 @@
 use auto_pilot::{
     action::get_next_action_from_openai,
+    audit::{input_target, verify_audit_log, AuditEvent, AuditLog},
+    cassette::{install_cassette, CassetteMode},
+    confirm::{confirm_action, ConfirmChoice},
+    constants::{RUNS_DIR, STABILITY_POLL_INTERVAL_MS, VAULT_FILE},
+    cost::{budget_exceeded, install_cost_tracker, load_prices, print_cost_summary},
+    display::VirtualDisplay,
+    emergency::{abort_reason, is_aborted, start_emergency_stop, wait_for_abort},
     initialize::{get_user_objective, initialize_messages},
-    operations::{keyboard_type, mouse_click, search},
-    parsers::{convert_string_to_json, parse_openai_response, ActionType},
-    summarize::summarize,
-    types::Message,
+    operations::{
+        keyboard_type, manage_window, mouse_click, release_all_input, search, simulate_action,
+    },
+    parsers::{
+        click_detail_position, convert_string_to_json, parse_action_override, parse_display_size,
+        parse_hex_color, parse_hotkey, parse_openai_response, parse_region, resolve_click_detail,
+        ActionType,
+    },
+    policy::Policy,
+    recorder::{unix_millis, Recorder},
+    redact::{black_out, Redactor},
+    refine::refine_click,
+    replay::replay,
+    report::write_report,
+    risk::assess_risk,
+    screen::{add_previous_action_to_image, capture_frame, wait_for_stable_screen},
+    secrets::{list_secrets, remove_secret, set_secret, Secrets},
+    summarize::{summarize, summarize_partial},
+    takeover::{detect_takeover, user_activity, wait_for_resume},
+    terminal::read_hidden_line,
+    timelapse::export_gif,
+    types::{
+        ActionOverride, ActionResult, Annotation, Budget, Config, Confirmation, DisplayServer,
+        GifOptions, GridOptions, GridStyle, Hotkey, Message, PreviousAction, PreviousStep,
+        RedactionStyle, Region, RegionTarget, ReplayOptions, RiskLevel, ScreenCorner,
+        StabilityOptions, StepTimings, TrajectoryStep, VirtualDisplayOptions,
+    },
 };
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use colored::Colorize;
-use std::{env, error::Error};
+use std::{
+    env,
+    error::Error,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 #[derive(Parser)]
 #[clap(
@@ -18,6 +54,12 @@ use std::{env, error::Error};
     long_about = "This is a tool that uses GPT4 Vision to operate your computer."
 )]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+
     /// The objective you want to achieve with the computer
     #[clap(short, long)]
     objective: Option<String>,
@@ -25,76 +67,966 @@ struct Cli {
     /// The grid interval to use when capturing the screen. Default is 300. The smaller the number, more number of lines will be drawn. (closer to pixel level)
     #[clap(short, long, default_value = "300")]
     grid_interval: i32,
+
+    /// Choose the grid interval automatically from the screen resolution, so the cells look the same size to the model on every screen. Overrides --grid-interval
+    #[clap(long)]
+    auto_grid: bool,
+
+    /// How the grid is labelled. `labels` writes percentages at every intersection, `rulers` writes them in margins outside the screenshot and `cells` labels every cell (A1, B1, ...) so the model clicks a cell by its label
+    #[clap(long, value_enum, default_value = "labels")]
+    grid_style: GridStyle,
+
+    /// The color of the grid lines as hex RGB or RGBA, e.g. 0000ff or 0000ff80 for half transparent lines
+    #[clap(long, default_value = "0000ff", value_parser = parse_hex_color)]
+    grid_color: [u8; 4],
+
+    /// The thickness of the grid lines in pixels
+    #[clap(long, default_value = "1")]
+    grid_thickness: u32,
+
+    /// How clickable locations are marked on the screenshot. `grid` draws percentage grid lines, `marks` draws numbered boxes around detected UI elements that the model clicks by number.
+    #[clap(short, long, value_enum, default_value = "grid")]
+    annotation: Annotation,
+
+    /// Refine every click with a second, zoomed-in screenshot around the chosen location before clicking
+    #[clap(long)]
+    refine: bool,
+
+    /// How much the area around a click is scaled up when refining
+    #[clap(long, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+    zoom_factor: u32,
+
+    /// The size in pixels of the square cropped around a click when refining
+    #[clap(long, default_value = "300", value_parser = clap::value_parser!(u32).range(1..))]
+    zoom_crop_size: u32,
+
+    /// Limit the agent to a rectangle of the screen given as x,y,width,height. Screenshots are cropped to it and clicks outside of it are refused
+    #[clap(long, value_parser = parse_region, conflicts_with = "window")]
+    region: Option<Region>,
+
+    /// Limit the agent to the first window whose title contains this text (X11 only). Screenshots are cropped to the window and clicks outside of it are refused
+    #[clap(long)]
+    window: Option<String>,
+
+    /// The directory in which every run is recorded into its own timestamped directory, with all screenshots, prompts, model responses and actions
+    #[clap(long, default_value = RUNS_DIR)]
+    runs_dir: String,
+
+    /// Show every action before it is executed and ask whether to run it, skip it, edit it or abort the run
+    #[clap(long)]
+    confirm: bool,
+
+    /// Semi-autonomous mode: only ask before actions whose risk is at least this level, e.g. clicking "Delete", "Send" or "Pay". High risk actions must be approved by typing yes
+    #[clap(long, value_enum)]
+    confirm_risk: Option<RiskLevel>,
+
+    /// Read the text around every click target with the tesseract command to assess its risk, for clicks the model doesn't describe well
+    #[clap(long)]
+    risk_ocr: bool,
+
+    /// Plan without touching the computer: actions are validated and logged, with the intended click drawn on the screenshot, but no input is sent. The model is told that its actions were simulated
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Hide this rectangle of the screen, given as x,y,width,height in screen coordinates, on every screenshot before it is sent to the model. Can be given several times
+    #[clap(long, value_parser = parse_region)]
+    redact_region: Vec<Region>,
+
+    /// Hide every window whose title contains this text on the screenshots (X11 only). Can be given several times
+    #[clap(long)]
+    redact_window: Vec<String>,
+
+    /// Hide text matching this regular expression on the screenshots, e.g. an email address or API key pattern. Text is found with the tesseract command, which must be installed. Can be given several times
+    #[clap(long)]
+    redact_text: Vec<String>,
+
+    /// How hidden areas look
+    #[clap(long, value_enum, default_value = "black")]
+    redact_style: RedactionStyle,
+
+    /// Don't pause when the pointer moves or the focused window changes without the agent, e.g. because someone uses the computer
+    #[clap(long)]
+    ignore_user_activity: bool,
+
+    /// Resume a run paused by user activity once the pointer and focused window stayed still for this many seconds, instead of waiting for Enter
+    #[clap(long)]
+    resume_after_idle: Option<u64>,
+
+    /// Stop the run once it cost this many US dollars, estimated from the price table. The step that reaches the limit is finished first
+    #[clap(long)]
+    max_cost: Option<f64>,
+
+    /// Stop the run once it used this many prompt and completion tokens. The step that reaches the limit is finished first
+    #[clap(long)]
+    max_tokens: Option<u64>,
+
+    /// A JSON file with the price of models in US dollars per million tokens, e.g. {"gpt-4o": {"prompt": 5.0, "completion": 15.0}}, overriding the built-in prices
+    #[clap(long)]
+    price_table: Option<PathBuf>,
+
+    /// Don't record the run
+    #[clap(long)]
+    no_record: bool,
+
+    /// Save every request to the model and its response in this new cassette file, to replay them later with --replay-cassette
+    #[clap(long, conflicts_with = "replay_cassette")]
+    record_cassette: Option<PathBuf>,
+
+    /// Answer requests to the model from a cassette recorded with --record-cassette instead of calling the model. Fails on requests that were not recorded
+    #[clap(long)]
+    replay_cassette: Option<PathBuf>,
+
+    /// Match cassette requests without comparing their screenshots, pointer position, open windows and loading time, so a cassette can be replayed on a screen that looks slightly different
+    #[clap(long)]
+    cassette_ignore_images: bool,
+}
+
+/// Options of a run that replays use as well.
+#[derive(Args)]
+struct RunArgs {
+    /// The fraction of pixels that may change between two screenshots for the screen to count as settled after an action
+    #[clap(long, default_value = "0.002")]
+    stable_threshold: f32,
+
+    /// How many settled screenshots in a row are needed before the next step
+    #[clap(long, default_value = "2")]
+    stable_frames: u32,
+
+    /// The longest time in milliseconds to wait for the screen to settle after an action
+    #[clap(long, default_value = "10000")]
+    stable_timeout: u64,
+
+    /// Run on a virtual X display started for this run instead of the current screen. `xvfb` is invisible and works without a physical display, `xephyr` opens the display as a window on the current screen so you can watch. The display is shut down on exit
+    #[clap(long, value_enum)]
+    virtual_display: Option<DisplayServer>,
+
+    /// The size of the virtual display as WIDTHxHEIGHT
+    #[clap(long, default_value = "1280x800", value_parser = parse_display_size, requires = "virtual_display")]
+    display_size: (u32, u32),
+
+    /// A window manager command to start on the virtual display, e.g. openbox. Without one, window actions are unavailable
+    #[clap(long, requires = "virtual_display")]
+    window_manager: Option<String>,
+
+    /// A shell command to start on the virtual display before the first step, e.g. firefox. Can be given several times
+    #[clap(long, requires = "virtual_display")]
+    startup: Vec<String>,
+
+    /// A JSON policy file with rules the actions must follow: denied action types, text that must not be typed, allowed applications and domains and screen areas or windows where clicks are refused. Refused actions are reported to the model, and skipped when replaying
+    #[clap(long, value_parser = Policy::load)]
+    policy: Option<Policy>,
+
+    /// Holding this key combination stops the run at once (X11 only), e.g. ctrl+alt+q or shift+escape
+    #[clap(long, default_value = "ctrl+alt+q", value_parser = parse_hotkey)]
+    abort_hotkey: Hotkey,
+
+    /// Moving the pointer into this corner of the screen stops the run at once
+    #[clap(long, value_enum, default_value = "top-left")]
+    fail_safe_corner: ScreenCorner,
+
+    /// Disable the abort hotkey and the fail-safe corner
+    #[clap(long)]
+    no_emergency_stop: bool,
+
+    /// An encrypted file with secrets the model can type by placeholder, e.g. {{secret:staging_pw}}, without ever seeing them. Its passphrase is read from AUTO_PILOT_VAULT_PASSPHRASE. Secrets can also be given as AUTO_PILOT_SECRET_<NAME> environment variables
+    #[clap(long)]
+    vault: Option<PathBuf>,
+
+    /// Append every input event sent to the computer to this tamper-evident audit log, with the time, action, click position, target window and digests of the screenshot and model response. Check it with `audit verify`
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Re-execute the actions of a recorded run on the current screen without calling the model
+    Replay {
+        /// The directory of the recorded run
+        run_dir: PathBuf,
+
+        /// Ask before every step whether to run it, skip it or stop
+        #[clap(long)]
+        step: bool,
+
+        /// Stop when a larger fraction of the screen than this differs from the screen recorded before the step, e.g. 0.1
+        #[clap(long)]
+        max_divergence: Option<f32>,
+
+        /// Replace the action of a step, e.g. `3=SKIP`, `4=TYPE hello` or `5=CLICK {"x": "50%", "y": "20%"}`. Can be given several times
+        #[clap(long = "override", value_parser = parse_action_override)]
+        overrides: Vec<(u32, ActionOverride)>,
+
+        #[clap(flatten)]
+        run: RunArgs,
+    },
+    /// Render a recorded run into a single HTML file that can be shared
+    Report {
+        /// The directory of the recorded run
+        run_dir: PathBuf,
+
+        /// Where to write the report, by default report.html in the run directory
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Export the screenshots of a recorded run as an animated GIF, with the action of every step drawn on it
+    Gif {
+        /// The directory of the recorded run
+        run_dir: PathBuf,
+
+        /// Where to write the GIF, by default timelapse.gif in the run directory
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// How long every step is shown, in milliseconds
+        #[clap(long, default_value = "1500")]
+        frame_duration: u32,
+
+        /// Factor applied to the size of the screenshots, smaller values give smaller files
+        #[clap(long, default_value = "0.5")]
+        scale: f32,
+    },
+    /// Check the audit log written with --audit-log
+    Audit {
+        #[clap(subcommand)]
+        command: AuditCommand,
+    },
+    /// Manage the secrets vault, by default secrets.vault or the file given with --vault
+    Secrets {
+        /// The vault file
+        #[clap(long, global = true)]
+        vault: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        command: SecretsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditCommand {
+    /// Check that no entry of an audit log was modified, removed or reordered and that the log was not truncated
+    Verify {
+        /// The audit log
+        log: PathBuf,
+
+        /// The last hash of the log, as printed at the end of the run, to also detect a log that was truncated together with its head file
+        #[clap(long)]
+        last_hash: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SecretsCommand {
+    /// Add or replace a secret, its value is read from stdin
+    Set {
+        /// The name used in the placeholder, e.g. staging_pw for {{secret:staging_pw}}
+        name: String,
+    },
+    /// Remove a secret
+    Remove { name: String },
+    /// List the names of the secrets in the vault
+    List,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let opts: Cli = Cli::parse();
 
+    if let Some(Command::Report { run_dir, output }) = &opts.command {
+        let report = write_report(run_dir, output.as_deref())?;
+        println!(
+            "{} Report written to {}",
+            "[Auto-Pilot-Computer]".blue(),
+            report.display()
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::Gif {
+        run_dir,
+        output,
+        frame_duration,
+        scale,
+    }) = &opts.command
+    {
+        let options = GifOptions {
+            frame_duration_ms: *frame_duration,
+            scale: *scale,
+        };
+        let gif = export_gif(run_dir, output.as_deref(), &options)?;
+        println!(
+            "{} GIF written to {}",
+            "[Auto-Pilot-Computer]".blue(),
+            gif.display()
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::Audit {
+        command: AuditCommand::Verify { log, last_hash },
+    }) = &opts.command
+    {
+        let head = verify_audit_log(log, last_hash.as_deref())?;
+        println!(
+            "{} The audit log is intact: {} entries, last hash {}",
+            "[Auto-Pilot-Computer]".blue(),
+            head.entries,
+            head.last_hash
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::Secrets { vault, command }) = &opts.command {
+        let vault = vault.as_deref().unwrap_or(Path::new(VAULT_FILE));
+        manage_secrets(vault, command)?;
+        return Ok(());
+    }
+
+    // Replays take the run options after the subcommand
+    let (run, replay_command) = match opts.command {
+        Some(Command::Replay {
+            run_dir,
+            step,
+            max_divergence,
+            overrides,
+            run,
+        }) => (run, Some((run_dir, step, max_divergence, overrides))),
+        _ => (opts.run, None),
+    };
+
+    let secrets = Secrets::load(run.vault.as_deref())?;
+
+    // Kept alive until the end of main, dropping it shuts the display down
+    let virtual_display = match run.virtual_display {
+        Some(server) => {
+            let virtual_display = VirtualDisplay::start(&VirtualDisplayOptions {
+                server,
+                width: run.display_size.0,
+                height: run.display_size.1,
+                window_manager: run.window_manager,
+                startup: run.startup,
+            })?;
+            println!(
+                "{} Running on virtual display {}",
+                "[Auto-Pilot-Computer]".blue(),
+                virtual_display.display
+            );
+            Some(virtual_display)
+        }
+        None => None,
+    };
+
+    if !run.no_emergency_stop {
+        start_emergency_stop(Some(&run.abort_hotkey), Some(run.fail_safe_corner));
+    }
+
+    let stability = StabilityOptions {
+        threshold: run.stable_threshold,
+        frames: run.stable_frames,
+        poll_interval_ms: STABILITY_POLL_INTERVAL_MS,
+        timeout_ms: run.stable_timeout,
+    };
+
+    if let Some((run_dir, step, max_divergence, overrides)) = replay_command {
+        let options = ReplayOptions {
+            step_manually: step,
+            max_divergence,
+            overrides,
+            stability,
+            secrets,
+            policy: run.policy,
+        };
+        let mut audit_log = match &run.audit_log {
+            Some(path) => Some(AuditLog::open(path)?),
+            None => None,
+        };
+        // Fails the process on divergence so scripted replays notice it
+        let replayed = replay(&run_dir, &options, audit_log.as_mut()).await;
+        if let Some(audit_log) = &audit_log {
+            println!(
+                "{} Audit log last hash: {}",
+                "[Auto-Pilot-Computer]".blue(),
+                audit_log.last_hash()
+            );
+        }
+        replayed?;
+        drop(virtual_display);
+        return Ok(());
+    }
+
     let objective = match opts.objective {
         Some(objective) => objective,
         None => get_user_objective()?,
     };
 
-    let grid_interval = opts.grid_interval;
+    let redaction_configured = !opts.redact_region.is_empty()
+        || !opts.redact_window.is_empty()
+        || !opts.redact_text.is_empty();
+    let redactor = if redaction_configured {
+        Some(Redactor::new(
+            opts.redact_region,
+            opts.redact_window,
+            &opts.redact_text,
+            opts.redact_style,
+        )?)
+    } else {
+        None
+    };
+
+    let config = Config {
+        grid: GridOptions {
+            interval: opts.grid_interval,
+            auto_interval: opts.auto_grid,
+            style: opts.grid_style,
+            color: opts.grid_color,
+            thickness: opts.grid_thickness,
+        },
+        annotation: opts.annotation,
+        refine: opts.refine,
+        zoom_factor: opts.zoom_factor,
+        zoom_crop_size: opts.zoom_crop_size,
+        stability,
+        region: match (opts.region, opts.window) {
+            (Some(region), _) => Some(RegionTarget::Rect(region)),
+            (None, Some(title)) => Some(RegionTarget::Window(title)),
+            (None, None) => None,
+        },
+        confirm: opts.confirm,
+        dry_run: opts.dry_run,
+        policy: run.policy,
+        pause_on_takeover: !opts.ignore_user_activity,
+        resume_after_idle_ms: opts.resume_after_idle.map(|seconds| seconds * 1000),
+        secrets,
+        redactor,
+        confirm_risk: opts.confirm_risk,
+        risk_ocr: opts.risk_ocr,
+    };
+
+    match (&opts.record_cassette, &opts.replay_cassette) {
+        (Some(path), _) => {
+            install_cassette(path, CassetteMode::Record, opts.cassette_ignore_images)?
+        }
+        (None, Some(path)) => {
+            install_cassette(path, CassetteMode::Replay, opts.cassette_ignore_images)?
+        }
+        (None, None) => {}
+    }
+
+    install_cost_tracker(
+        load_prices(opts.price_table.as_deref())?,
+        Budget {
+            max_tokens: opts.max_tokens,
+            max_cost_usd: opts.max_cost,
+        },
+    )?;
 
     env::set_var("RUST_BACKTRACE", "1");
     let mut messages = initialize_messages(&objective);
 
-    let result = run_auto_pilot(&mut messages, &objective, grid_interval).await;
-    if let Err(e) = result {
-        eprintln!("Error occurred: {}", e);
+    let mut recorder = if opts.no_record {
+        None
+    } else {
+        let recorder = Recorder::start(&opts.runs_dir, &objective)?;
+        println!(
+            "{} Recording to {}",
+            "[Auto-Pilot-Computer]".blue(),
+            recorder.dir.display()
+        );
+        Some(recorder)
+    };
+
+    let mut audit_log = match &run.audit_log {
+        Some(path) => Some(AuditLog::open(path)?),
+        None => None,
+    };
+
+    let mut outcome = tokio::select! {
+        result = run_auto_pilot(&mut messages, &objective, &config, recorder.as_mut(), audit_log.as_mut()) => {
+            match result {
+                Ok(outcome) => outcome.to_string(),
+                Err(e) => {
+                    eprintln!("Error occurred: {}", e);
+                    format!("error: {}", e)
+                }
+            }
+        }
+        // Return normally on Ctrl+C so the virtual display is torn down
+        _ = tokio::signal::ctrl_c() => {
+            eprintln!("Interrupted");
+            "interrupted".to_string()
+        }
+        // Cancels a step that is waiting for the model
+        _ = wait_for_abort() => "aborted".to_string(),
+    };
+
+    let mut summary = None;
+    if let Some(reason) = abort_reason() {
+        release_all_input();
+        outcome = format!("aborted: {}", reason);
+        match summarize_partial(&mut messages, &objective, &config).await {
+            Ok(partial_summary) => {
+                println!("{}: {}", "Progress".bright_magenta(), partial_summary);
+                summary = Some(partial_summary);
+            }
+            Err(e) => eprintln!("Failed to summarize the progress: {}", e),
+        }
+    }
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.finish(&outcome, summary.as_deref())?;
+    }
+    print_cost_summary();
+    if let Some(audit_log) = &audit_log {
+        println!(
+            "{} Audit log last hash: {}",
+            "[Auto-Pilot-Computer]".blue(),
+            audit_log.last_hash()
+        );
     }
+    drop(virtual_display);
 
     Ok(())
 }
 
+fn manage_secrets(vault: &Path, command: &SecretsCommand) -> Result<(), String> {
+    match command {
+        SecretsCommand::Set { name } => {
+            if io::stdin().is_terminal() {
+                eprint!("Value of {}: ", name);
+            }
+            let value = read_hidden_line()?;
+            let value = value.trim_end_matches(['\r', '\n']);
+            set_secret(vault, name, value)?;
+            println!("Saved {} in {}", name, vault.display());
+        }
+        SecretsCommand::Remove { name } => {
+            remove_secret(vault, name)?;
+            println!("Removed {} from {}", name, vault.display());
+        }
+        SecretsCommand::List => {
+            for name in list_secrets(vault)? {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the agent until it is done, returning how the run ended.
 async fn run_auto_pilot(
     messages: &mut Vec<Message>,
     objective: &str,
-    grid_interval: i32,
-) -> Result<(), Box<dyn Error>> {
+    config: &Config,
+    mut recorder: Option<&mut Recorder>,
+    mut audit_log: Option<&mut AuditLog>,
+) -> Result<&'static str, Box<dyn Error>> {
     let mut loop_count = 0;
+    let mut previous_step: Option<PreviousStep> = None;
+    let mut last_click: Option<(f32, f32)> = None;
+    // Where the agent left the pointer and focus, to notice when someone else moves them
+    let mut expected_activity = config.pause_on_takeover.then(user_activity);
     loop {
-        let response = get_next_action_from_openai(messages, objective, grid_interval).await?;
+        if is_aborted() {
+            return Ok("aborted");
+        }
+        if let Some(reason) = budget_exceeded() {
+            println!(
+                "{} {} Stopping, {}",
+                "[Auto-Pilot-Computer]".blue(),
+                "[Budget]".bright_magenta(),
+                reason
+            );
+            return Ok("budget_exceeded");
+        }
+        let started_at_ms = unix_millis();
+        let step_start = Instant::now();
+        let (response, observation, call) =
+            get_next_action_from_openai(messages, objective, config, previous_step.as_ref())
+                .await?;
+        let mut timings = StepTimings {
+            observe_ms: (step_start.elapsed().as_millis() as u64).saturating_sub(call.duration_ms),
+            model_ms: call.duration_ms,
+            ..Default::default()
+        };
+        let mut previous_action = None;
+        let mut click = None;
+        let mut refinement = None;
+        let mut confirmation = None;
+        let mut feedback = None;
+        // Set when this step ends the run
+        let mut outcome = None;
+
+        // Frame to compare against once the action is done
+        let before_action = capture_frame(observation.region.as_ref()).ok();
+
+        let (mut action_type, mut action_detail) = parse_openai_response(&response)?;
 
-        let (action_type, action_detail) = parse_openai_response(&response)?;
+        // Clicks are resolved to a position on the screen before anything is executed. A
+        // click on a mark or cell that isn't on the screen is a mistake the model can fix
+        let mut unresolved_click = None;
+        if let ActionType::Click = ActionType::from_str(&action_type) {
+            let resolved_click = convert_string_to_json(&action_detail)
+                .map_err(|e| format!("Invalid click {}: {}", action_detail, e))
+                .and_then(|click_detail| resolve_click_detail(click_detail, &observation));
+            match resolved_click {
+                Err(e) => unresolved_click = Some(e),
+                Ok(mut click_detail) => {
+                    if config.refine {
+                        let refine_start = Instant::now();
+                        match refine_click(objective, click_detail.clone(), &observation, config)
+                            .await
+                        {
+                            Ok(Some(refined)) => {
+                                click_detail = refined.click_detail.clone();
+                                refinement = Some(refined);
+                            }
+                            Ok(None) => {}
+                            Err(e) => eprintln!("Refinement failed, using the coarse click: {}", e),
+                        }
+                        timings.refine_ms = refine_start.elapsed().as_millis() as u64;
+                    }
+                    click = Some(click_detail);
+                }
+            }
+        }
 
-        let function_response = match ActionType::from_str(&action_type) {
-            ActionType::Search => search(&action_detail),
-            ActionType::Type => keyboard_type(&action_detail),
-            ActionType::Click => {
-                let click_detail = convert_string_to_json(&action_detail)?;
-                mouse_click(&click_detail)
+        // Malformed window JSON is fed back like an unresolvable click
+        let mut invalid_window = None;
+        if let ActionType::Window = ActionType::from_str(&action_type) {
+            if let Err(e) = convert_string_to_json(&action_detail) {
+                invalid_window = Some(format!("Invalid window action {}: {}", action_detail, e));
             }
-            ActionType::Unknown => {
+        }
+
+        // The model call or the refinement can take a while, don't act after a stop
+        if is_aborted() {
+            return Ok("aborted");
+        }
+
+        let mut skipped = None;
+        if let Some(e) = unresolved_click {
+            feedback = Some(format!(
+                "Your previous click could not be performed and was not executed: {}. Look at the new screenshot and choose the click again.",
+                e
+            ));
+            skipped = Some(format!("Refused click: {}", e));
+        }
+        if let Some(e) = invalid_window {
+            feedback = Some(format!(
+                "Your previous WINDOW action could not be performed and was not executed: {}. Answer with the window action as valid JSON.",
+                e
+            ));
+            skipped = Some(format!("Refused window action: {}", e));
+        }
+        let executable = matches!(
+            ActionType::from_str(&action_type),
+            ActionType::Click | ActionType::Type | ActionType::Search | ActionType::Window
+        );
+        // Never act on a screen the user changed while the model was thinking
+        if let (true, None, Some(expected)) = (executable, &skipped, &expected_activity) {
+            if let Some(takeover) = detect_takeover(expected) {
+                wait_for_resume(&takeover, config.resume_after_idle_ms).await?;
+                feedback = Some("The user used the computer while you chose your previous action, so it was not executed. Look at the new screenshot and choose the next action again.".to_string());
+                skipped = Some(format!("Not executed, the user took over: {}", takeover));
+            }
+        }
+        // Only assessed to decide about confirmations, it may need OCR
+        let assess = config.confirm || config.confirm_risk.is_some();
+        let risk = if assess && executable && skipped.is_none() {
+            Some(assess_risk(
+                &action_type,
+                &action_detail,
+                click.as_ref(),
+                &observation,
+                config.risk_ocr,
+            ))
+        } else {
+            None
+        };
+        let risky = matches!(
+            (&risk, config.confirm_risk),
+            (Some(risk), Some(threshold)) if risk.level >= threshold
+        );
+        if (config.confirm || risky) && executable && skipped.is_none() {
+            let preview = pending_action(&action_type, &action_detail, click.as_ref(), last_click);
+            match confirm_action(
+                &action_type,
+                &action_detail,
+                preview.as_ref(),
+                risk.as_ref(),
+                &observation,
+            )
+            .await?
+            {
+                ConfirmChoice::Approve => confirmation = Some(Confirmation::Approved),
+                ConfirmChoice::Skip => {
+                    confirmation = Some(Confirmation::Skipped);
+                    feedback = Some("The user skipped your previous action, it was not executed. Choose a different action.".to_string());
+                    skipped = Some("Skipped by the user".to_string());
+                }
+                ConfirmChoice::Edit {
+                    action,
+                    detail,
+                    click: edited_click,
+                } => {
+                    feedback = Some(format!(
+                        "The user replaced your previous action with {} {}.",
+                        action, detail
+                    ));
+                    click = edited_click;
+                    confirmation = Some(Confirmation::Edited {
+                        original_action: std::mem::replace(&mut action_type, action),
+                        original_detail: std::mem::replace(&mut action_detail, detail),
+                    });
+                }
+                ConfirmChoice::Abort => {
+                    confirmation = Some(Confirmation::Aborted);
+                    outcome = Some("aborted");
+                    skipped = Some("Aborted by the user".to_string());
+                }
+            }
+        }
+
+        let mut policy_violation = None;
+        if let (None, true, Some(policy)) = (&skipped, executable, &config.policy) {
+            if let Err(violation) = policy.check(
+                &action_type,
+                &action_detail,
+                click.as_ref(),
+                observation.region.as_ref(),
+            ) {
+                feedback = Some(format!(
+                    "Your previous action was refused by the policy and not executed: {}. Choose a different action.",
+                    violation
+                ));
+                skipped = Some(format!("Refused by policy: {}", violation));
+                policy_violation = Some(violation);
+            }
+        }
+
+        // The answer to a confirmation can come after a stop
+        if is_aborted() {
+            return Ok("aborted");
+        }
+
+        // Looked up before acting, since the action can move the focus
+        let target = match (&skipped, &audit_log) {
+            (None, Some(_)) if executable && !config.dry_run => {
+                Some(input_target(click.as_ref(), observation.region.as_ref()))
+            }
+            _ => None,
+        };
+
+        let mut simulated = false;
+        let mut intended_path = None;
+        let act_start = Instant::now();
+        let action_result = match (skipped, ActionType::from_str(&action_type)) {
+            (Some(reason), _) => ActionResult::not_executed(reason),
+            (None, ActionType::Search) if config.region.is_some() => ActionResult::not_executed(
+                "Refused: SEARCH is disabled while the agent is limited to a region".to_string(),
+            ),
+            (None, ActionType::Window) if config.region.is_some() => ActionResult::not_executed(
+                "Refused: WINDOW is disabled while the agent is limited to a region".to_string(),
+            ),
+            (None, _) if config.dry_run && executable => {
+                simulated = true;
+                previous_action =
+                    pending_action(&action_type, &action_detail, click.as_ref(), last_click);
+                if let Some(intended) = &previous_action {
+                    let path = "screenshots/screenshot_intended.png";
+                    add_previous_action_to_image(&observation.screenshot_path, path, intended)?;
+                    intended_path = Some(path);
+                }
+                last_click = match ActionType::from_str(&action_type) {
+                    ActionType::Click => click.as_ref().and_then(click_detail_position),
+                    ActionType::Type => last_click,
+                    _ => None,
+                };
+                feedback = Some("This is a dry run: your previous action was simulated, not executed, so the screen did not change because of it. Continue with the next action as if it had worked.".to_string());
+                ActionResult::not_executed(simulate_action(
+                    &action_type,
+                    &action_detail,
+                    click.as_ref(),
+                    observation.region.as_ref(),
+                    &config.secrets,
+                ))
+            }
+            (None, ActionType::Search) => {
+                last_click = None;
+                search(&action_detail)
+            }
+            (None, ActionType::Type) => {
+                previous_action = Some(PreviousAction::Type {
+                    text: action_detail.clone(),
+                    focus: last_click,
+                });
+                keyboard_type(&action_detail, &config.secrets)
+            }
+            (None, ActionType::Window) => {
+                last_click = None;
+                match convert_string_to_json(&action_detail) {
+                    Ok(window_detail) => manage_window(&window_detail),
+                    Err(e) => ActionResult::not_executed(format!(
+                        "Invalid window action {}: {}",
+                        action_detail, e
+                    )),
+                }
+            }
+            (None, ActionType::Click) => {
+                let click_detail = click.as_ref().ok_or("CLICK without a position")?;
+                last_click = click_detail_position(click_detail);
+                previous_action = last_click.map(|(x, y)| PreviousAction::Click { x, y });
+                mouse_click(click_detail, observation.region.as_ref())
+            }
+            (None, ActionType::Unknown) => {
                 eprintln!("Something went wrong :(");
                 eprintln!("AI response: {}", response);
                 eprintln!("action_type: {}", action_type);
                 eprintln!("action_detail: {}", action_detail);
-                break;
+                outcome = Some("unknown_action");
+                ActionResult::not_executed("Unknown action".to_string())
             }
-            ActionType::Done => {
-                let summary = summarize(messages, objective).await?;
+            (None, ActionType::Done) => {
+                let summary = summarize(messages, objective, config).await?;
                 println!("{}: {}", "Summary".bright_magenta(), summary);
-                break;
+                outcome = Some("done");
+                ActionResult::not_executed(summary)
             }
         };
+        timings.act_ms = act_start.elapsed().as_millis() as u64;
+        let function_response = action_result.message;
 
-        println!(
-            "{} {} {} {} {}",
-            "[Auto-Pilot-Computer]".blue(),
-            "[Act]".bright_magenta(),
-            action_type,
-            "COMPLETE".green(),
-            function_response
-        );
+        if let (true, Some(audit_log), Some(target)) =
+            (action_result.executed, audit_log.as_mut(), target)
+        {
+            audit_log.record(&AuditEvent {
+                action: &action_type,
+                detail: &action_detail,
+                target,
+                screenshot_path: &observation.screenshot_path,
+                response: Some(&call.response),
+                result: &function_response,
+            })?;
+        }
+
+        let screen_change = if outcome.is_none() {
+            println!(
+                "{} {} {} {} {}",
+                "[Auto-Pilot-Computer]".blue(),
+                "[Act]".bright_magenta(),
+                action_type,
+                "COMPLETE".green(),
+                function_response
+            );
+
+            // Nothing to wait for when the action was only simulated
+            if simulated {
+                None
+            } else {
+                let settle_start = Instant::now();
+                let screen_change = wait_for_stable_screen(
+                    before_action.as_ref(),
+                    &config.stability,
+                    observation.region.as_ref(),
+                )
+                .await?;
+                timings.settle_ms = settle_start.elapsed().as_millis() as u64;
+                Some(screen_change)
+            }
+        } else {
+            None
+        };
+
+        if let Some(recorder) = recorder.as_mut() {
+            let step = loop_count + 1;
+            let refine = match &refinement {
+                Some(refinement) => Some(recorder.save_refinement(step, refinement)?),
+                None => None,
+            };
+            let intended_screenshot = match intended_path {
+                Some(path) => Some(recorder.save_image(step, "intended", path)?),
+                None => None,
+            };
+            let frame = match &before_action {
+                Some(frame) => {
+                    let mut frame = frame.clone();
+                    black_out(&mut frame, &observation.redactions);
+                    Some(recorder.save_frame(step, "frame", &frame)?)
+                }
+                None => None,
+            };
+            recorder.record_step(&TrajectoryStep {
+                step,
+                started_at_ms,
+                screenshot: recorder.save_image(
+                    step,
+                    "screenshot",
+                    &observation.screenshot_path,
+                )?,
+                annotated_screenshot: recorder.save_image(
+                    step,
+                    "annotated",
+                    &observation.annotated_path,
+                )?,
+                frame,
+                width: observation.width,
+                height: observation.height,
+                region: observation.region,
+                pointer: observation.pointer,
+                marks: observation.marks.clone(),
+                prompt: call.prompt,
+                response: call.response,
+                usage: call.usage,
+                action: action_type,
+                action_detail,
+                click,
+                refine,
+                confirmation,
+                intended_screenshot,
+                policy_violation,
+                redactions: observation.redactions.clone(),
+                risk,
+                result: function_response,
+                executed: action_result.executed,
+                screen_change,
+                timings,
+            })?;
+        }
+
+        if let Some(outcome) = outcome {
+            return Ok(outcome);
+        }
+
+        if config.pause_on_takeover {
+            expected_activity = Some(user_activity());
+        }
+
+        previous_step = Some(PreviousStep {
+            action: previous_action,
+            screen_change,
+            feedback,
+        });
 
         loop_count += 1;
         if loop_count > 15 {
-            break;
+            return Ok("max_steps");
         }
     }
+}
 
-    Ok(())
+/// Where an action that is about to run will land, for previews and dry runs.
+fn pending_action(
+    action_type: &str,
+    action_detail: &str,
+    click: Option<&serde_json::Value>,
+    last_click: Option<(f32, f32)>,
+) -> Option<PreviousAction> {
+    match ActionType::from_str(action_type) {
+        ActionType::Click => click
+            .and_then(click_detail_position)
+            .map(|(x, y)| PreviousAction::Click { x, y }),
+        ActionType::Type => Some(PreviousAction::Type {
+            text: action_detail.to_string(),
+            focus: last_click,
+        }),
+        _ => None,
+    }
 }
@@ -0,0 +1,49 @@
+use std::process::Command;
+
+/// A word recognized on an image, in pixels of the image.
+pub struct OcrWord {
+    /// Page, block, paragraph and line number, words with the same value are on one line
+    pub line: (u32, u32, u32, u32),
+    pub text: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Words recognized by the `tesseract` command, in reading order.
+pub fn ocr_words(path: &str) -> Result<Vec<OcrWord>, String> {
+    let output = Command::new("tesseract")
+        .args([path, "stdout", "tsv"])
+        .output()
+        .map_err(|e| format!("Reading text needs the tesseract command: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "tesseract failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    // Columns: level page_num block_num par_num line_num word_num left top width height conf text
+    let tsv = String::from_utf8_lossy(&output.stdout);
+    let words = tsv
+        .lines()
+        .skip(1)
+        .filter_map(|row| {
+            let columns: Vec<&str> = row.split('\t').collect();
+            if columns.len() < 12 || columns[0] != "5" || columns[11].trim().is_empty() {
+                return None;
+            }
+            let number = |i: usize| columns[i].parse::<u32>().ok();
+            Some(OcrWord {
+                line: (number(1)?, number(2)?, number(3)?, number(4)?),
+                text: columns[11].to_string(),
+                x: number(6)?,
+                y: number(7)?,
+                width: number(8)?,
+                height: number(9)?,
+            })
+        })
+        .collect();
+    Ok(words)
+}
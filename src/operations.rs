@@ -1,9 +1,30 @@
-use crate::{parsers::convert_percent_to_decimal, screen::get_screen_size};
+use crate::{
+    emergency::{is_aborted, move_pointer},
+    parsers::{convert_percent_to_decimal, ActionType},
+    screen::get_screen_size,
+    secrets::Secrets,
+    types::{ActionResult, Region},
+    window::{close_window, find_window, focus_window, minimize_window, move_resize_window},
+};
 use enigo::{Enigo, Key, KeyboardControllable, MouseButton, MouseControllable};
 
-pub fn keyboard_type(text: &str) -> String {
+/// Types `text` followed by Enter. Secret placeholders are replaced with their values only
+/// here, the result only contains the placeholders.
+pub fn keyboard_type(text: &str, secrets: &Secrets) -> ActionResult {
+    let keys = match secrets.substitute(text) {
+        Ok(keys) => keys,
+        Err(e) => return ActionResult::not_executed(format!("Refused: {}", e)),
+    };
     let mut enigo = Enigo::new();
-    for c in text.chars() {
+    for (i, c) in keys.chars().enumerate() {
+        if is_aborted() {
+            let message = format!("Typing stopped: {}", text);
+            // Part of the text may already be typed
+            return ActionResult {
+                executed: i > 0,
+                message,
+            };
+        }
         match c {
             '/' => {
                 enigo.key_sequence("/");
@@ -16,10 +37,10 @@ pub fn keyboard_type(text: &str) -> String {
 
     enigo.key_down(Key::Return);
 
-    format!("Type: {}", text)
+    ActionResult::executed(format!("Type: {}", text))
 }
 
-pub fn search(text: &str) -> String {
+pub fn search(text: &str) -> ActionResult {
     let mut enigo = Enigo::new();
     // open the search in MacOS
     enigo.key_down(Key::Meta);
@@ -29,13 +50,53 @@ pub fn search(text: &str) -> String {
     std::thread::sleep(std::time::Duration::from_secs(1));
 
     for c in text.chars() {
+        if is_aborted() {
+            return ActionResult::executed(format!("Search stopped: {}", text));
+        }
         enigo.key_click(Key::Layout(c));
     }
     enigo.key_down(Key::Return);
-    return format!("Open program: {}", text);
+    return ActionResult::executed(format!("Open program: {}", text));
+}
+
+/// Releases every modifier and mouse button, so a run stopped in the middle of an action
+/// doesn't leave any of them held down.
+pub fn release_all_input() {
+    let mut enigo = Enigo::new();
+    for key in [Key::Shift, Key::Control, Key::Alt, Key::Meta, Key::Return] {
+        enigo.key_up(key);
+    }
+    for button in [MouseButton::Left, MouseButton::Middle, MouseButton::Right] {
+        enigo.mouse_up(button);
+    }
 }
 
-pub fn click_at_percentage(x_percentage: &str, y_percentage: &str) -> String {
+/// Clicks at a position given in percentages of the screen, or of `region` when the agent is
+/// limited to one. Clicks that fall outside of the region are refused.
+pub fn click_at_percentage(
+    x_percentage: &str,
+    y_percentage: &str,
+    region: Option<&Region>,
+) -> ActionResult {
+    let (x_pixel, y_pixel) = match click_position(x_percentage, y_percentage, region) {
+        Ok(position) => position,
+        Err(refusal) => return ActionResult::not_executed(refusal),
+    };
+
+    let mut enigo = Enigo::new();
+    move_pointer(&mut enigo, x_pixel, y_pixel);
+    enigo.mouse_click(MouseButton::Left);
+
+    ActionResult::executed(format!("Click: x: {}, y: {}", x_pixel, y_pixel))
+}
+
+/// Screen position of a click given in percentages, or the refusal when it falls outside of
+/// `region`.
+pub fn click_position(
+    x_percentage: &str,
+    y_percentage: &str,
+    region: Option<&Region>,
+) -> Result<(i32, i32), String> {
     let x_decimal = match convert_percent_to_decimal(x_percentage) {
         Ok(x_decimal) => x_decimal,
         Err(_) => 0.0,
@@ -46,30 +107,133 @@ pub fn click_at_percentage(x_percentage: &str, y_percentage: &str) -> String {
         Err(_) => 0.0,
     };
 
-    let (screen_width, screen_height) = match get_screen_size() {
-        Ok((screen_width, screen_height)) => (screen_width, screen_height),
-        Err(_) => (0, 0),
-    };
-
-    let x_pixel = (x_decimal * screen_width as f32).round() as i32;
-    let y_pixel = (y_decimal * screen_height as f32).round() as i32;
-
-    let mut enigo = Enigo::new();
-    enigo.mouse_move_to(x_pixel, y_pixel);
-    enigo.mouse_click(MouseButton::Left);
+    match region {
+        Some(region) => {
+            let x_pixel = region.x + (x_decimal * region.width as f32).round() as i32;
+            let y_pixel = region.y + (y_decimal * region.height as f32).round() as i32;
+            if !region.contains(x_pixel, y_pixel) {
+                return Err(format!(
+                    "Refused click: x: {}, y: {} is outside of the allowed region",
+                    x_pixel, y_pixel
+                ));
+            }
+            Ok((x_pixel, y_pixel))
+        }
+        None => {
+            let (screen_width, screen_height) = match get_screen_size() {
+                Ok((screen_width, screen_height)) => (screen_width, screen_height),
+                Err(_) => (0, 0),
+            };
 
-    format!("Click: x: {}, y: {}", x_pixel, y_pixel)
+            Ok((
+                (x_decimal * screen_width as f32).round() as i32,
+                (y_decimal * screen_height as f32).round() as i32,
+            ))
+        }
+    }
 }
 
-pub fn mouse_click(click_detail: &serde_json::Value) -> String {
+pub fn mouse_click(click_detail: &serde_json::Value, region: Option<&Region>) -> ActionResult {
     match (click_detail["x"].as_str(), click_detail["y"].as_str()) {
         (Some(x), Some(y)) if !x.is_empty() && !y.is_empty() => {
-            click_at_percentage(x, y);
-            format!(
+            let click_result = click_at_percentage(x, y, region);
+            if !click_result.executed {
+                return click_result;
+            }
+            ActionResult::executed(format!(
                 "Click: x: {}, y: {}, description: {}, reason: {}",
                 x, y, click_detail["description"], click_detail["reason"]
+            ))
+        }
+        _ => ActionResult::not_executed("We failed to click".to_string()),
+    }
+}
+
+pub fn manage_window(window_detail: &serde_json::Value) -> ActionResult {
+    let (action, title) = match (
+        window_detail["action"].as_str(),
+        window_detail["title"].as_str(),
+    ) {
+        (Some(action), Some(title)) => (action, title),
+        _ => {
+            return ActionResult::not_executed(
+                "We failed to manage the window: action or title is missing".to_string(),
             )
         }
-        _ => "We failed to click".to_string(),
+    };
+
+    let window = match find_window(title) {
+        Ok(window) => window,
+        Err(e) => {
+            return ActionResult::not_executed(format!("We failed to manage the window: {}", e))
+        }
+    };
+
+    let as_i32 = |key: &str| window_detail[key].as_i64().map(|value| value as i32);
+    let as_u32 = |key: &str| window_detail[key].as_u64().map(|value| value as u32);
+    let result = match action {
+        "focus" => focus_window(window.id),
+        "move" => move_resize_window(window.id, as_i32("x"), as_i32("y"), None, None),
+        "resize" => move_resize_window(window.id, None, None, as_u32("width"), as_u32("height")),
+        "minimize" => minimize_window(window.id),
+        "close" => close_window(window.id),
+        _ => Err(format!("Unknown window action {}", action)),
+    };
+
+    match result {
+        Ok(()) => ActionResult::executed(format!("Window: {} \"{}\"", action, window.title)),
+        Err(e) => ActionResult::not_executed(format!(
+            "We failed to {} the window \"{}\": {}",
+            action, window.title, e
+        )),
     }
 }
+
+/// Validates an action like it would be executed, without sending any input.
+pub fn simulate_action(
+    action: &str,
+    detail: &str,
+    click: Option<&serde_json::Value>,
+    region: Option<&Region>,
+    secrets: &Secrets,
+) -> String {
+    let simulated = match ActionType::from_str(action) {
+        ActionType::Click => {
+            let position =
+                click.and_then(|click| Some((click["x"].as_str()?, click["y"].as_str()?)));
+            match position {
+                Some((x, y)) => match click_position(x, y, region) {
+                    Ok((x_pixel, y_pixel)) => format!("Click: x: {}, y: {}", x_pixel, y_pixel),
+                    Err(refusal) => return refusal,
+                },
+                None => return "We failed to click".to_string(),
+            }
+        }
+        ActionType::Type => match secrets.substitute(detail) {
+            Ok(_) => format!("Type: {}", detail),
+            Err(e) => return format!("Refused: {}", e),
+        },
+        ActionType::Search => format!("Open program: {}", detail),
+        ActionType::Window => {
+            let window_detail =
+                serde_json::from_str::<serde_json::Value>(detail).unwrap_or_default();
+            match (
+                window_detail["action"].as_str(),
+                window_detail["title"].as_str(),
+            ) {
+                (Some(action), Some(title)) => match find_window(title) {
+                    Ok(window) => format!("Window: {} \"{}\"", action, window.title),
+                    Err(e) => return format!("We failed to manage the window: {}", e),
+                },
+                _ => {
+                    return "We failed to manage the window: action or title is missing".to_string()
+                }
+            }
+        }
+        ActionType::Done | ActionType::Unknown => {
+            return format!("Nothing to simulate for {}", action)
+        }
+    };
+
+    format!("Simulated, not executed: {}", simulated)
+}
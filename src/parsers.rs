@@ -1,6 +1,16 @@
 use crate::{
-    prompts::{SUMMARY_PROMPT, VISION_PROMPT},
-    types::{Message, Role, TextMessage},
+    prompts::{
+        CELLS_CLICK_EXAMPLE, CELLS_CLICK_FORMAT, CELLS_GUIDE, GRID_CLICK_EXAMPLE,
+        GRID_CLICK_FORMAT, GRID_GUIDE, MARKS_CLICK_EXAMPLE, MARKS_CLICK_FORMAT, MARKS_GUIDE,
+        PARTIAL_SUMMARY_PROMPT, REFINE_PROMPT, RULERS_GUIDE, SECRETS_GUIDE, SUMMARY_PROMPT,
+        VISION_PROMPT, WINDOW_ACTIONS,
+    },
+    screen::cell_center,
+    secrets::placeholder,
+    types::{
+        ActionOverride, Annotation, Config, GridStyle, Hotkey, Mark, Message, Observation,
+        PreviousAction, Region, Role, ScreenChange, TextMessage,
+    },
 };
 use regex::Regex;
 
@@ -8,6 +18,7 @@ pub enum ActionType {
     Search,
     Type,
     Click,
+    Window,
     Unknown,
     Done,
 }
@@ -19,6 +30,7 @@ impl ActionType {
             s if s.starts_with("CLICK") => ActionType::Click,
             s if s.starts_with("TYPE") => ActionType::Type,
             s if s.starts_with("SEARCH") => ActionType::Search,
+            s if s.starts_with("WINDOW") => ActionType::Window,
             _ => ActionType::Unknown,
         }
     }
@@ -28,16 +40,138 @@ pub fn format_summary_prompt(objective: &str) -> String {
     format!("{}", SUMMARY_PROMPT.replace("{objective}", objective))
 }
 
-pub fn format_vision_prompt(objective: &str, previous_action: &str) -> String {
+pub fn format_partial_summary_prompt(objective: &str) -> String {
+    PARTIAL_SUMMARY_PROMPT.replace("{objective}", objective)
+}
+
+pub fn format_refine_prompt(objective: &str, description: &str) -> String {
+    REFINE_PROMPT
+        .replace("{description}", description)
+        .replace("{objective}", objective)
+}
+
+pub fn format_vision_prompt(
+    objective: &str,
+    previous_action: &str,
+    config: &Config,
+    observation: &Observation,
+) -> String {
+    let (annotation_guide, click_format, click_example) =
+        match (config.annotation, config.grid.style) {
+            (Annotation::Grid, GridStyle::Labels) => {
+                (GRID_GUIDE, GRID_CLICK_FORMAT, GRID_CLICK_EXAMPLE)
+            }
+            (Annotation::Grid, GridStyle::Rulers) => {
+                (RULERS_GUIDE, GRID_CLICK_FORMAT, GRID_CLICK_EXAMPLE)
+            }
+            (Annotation::Grid, GridStyle::Cells) => {
+                (CELLS_GUIDE, CELLS_CLICK_FORMAT, CELLS_CLICK_EXAMPLE)
+            }
+            (Annotation::Marks, _) => (MARKS_GUIDE, MARKS_CLICK_FORMAT, MARKS_CLICK_EXAMPLE),
+        };
+
     let previous_action_formatted = if !previous_action.is_empty() {
         format!("Here was the previous action you took: {}", previous_action)
     } else {
         String::new()
     };
 
+    let mut screen_context = Vec::new();
+    if let Some((x, y)) = observation.pointer {
+        screen_context.push(format!(
+            "The mouse pointer is currently at {{ \"x\": \"{:.0}%\", \"y\": \"{:.0}%\" }}, it is circled in red on the screenshot.",
+            x as f32 / observation.width as f32 * 100.0,
+            y as f32 / observation.height as f32 * 100.0
+        ));
+    }
+
+    if !observation.redactions.is_empty() {
+        screen_context.push("Some areas of the screenshot are hidden for privacy, they are blacked out or blurred. Don't try to read them.".to_string());
+    }
+
+    if observation.region.is_some() {
+        screen_context.push("The screenshot only shows the part of the screen you are allowed to use. Clicks outside of it are refused and SEARCH is disabled.".to_string());
+    }
+
+    let previous_step = observation.previous_step.clone().unwrap_or_default();
+    match &previous_step.action {
+        Some(PreviousAction::Click { x, y }) => screen_context.push(format!(
+            "Your previous CLICK landed at {{ \"x\": \"{:.0}%\", \"y\": \"{:.0}%\" }}, it is marked with a green crosshair on the screenshot. If the crosshair is not on the element you meant to click, correct your estimate.",
+            x * 100.0,
+            y * 100.0
+        )),
+        Some(PreviousAction::Type {
+            text,
+            focus: Some(_),
+        }) => screen_context.push(format!(
+            "You previously typed \"{}\" into the element highlighted in yellow on the screenshot. If the text is not there, the element was not focused.",
+            text
+        )),
+        Some(PreviousAction::Type { text, focus: None }) => screen_context.push(format!(
+            "You previously typed \"{}\" without clicking on an element first.",
+            text
+        )),
+        None => {}
+    }
+    match previous_step.screen_change {
+        Some(ScreenChange { changed: false, .. }) => screen_context.push(
+            "Your previous action had no visible effect, the screen looks the same as before it. Try something different.".to_string(),
+        ),
+        Some(ScreenChange {
+            stable: false,
+            waited_ms,
+            ..
+        }) => screen_context.push(format!(
+            "The screen was still changing {:.0} seconds after your previous action, something may still be loading.",
+            waited_ms as f32 / 1000.0
+        )),
+        _ => {}
+    }
+
+    if let Some(feedback) = &previous_step.feedback {
+        screen_context.push(feedback.clone());
+    }
+
+    if !config.secrets.is_empty() {
+        let placeholders: Vec<String> = config
+            .secrets
+            .names()
+            .into_iter()
+            .map(placeholder)
+            .collect();
+        screen_context.push(SECRETS_GUIDE.replace("{secrets}", &placeholders.join(", ")));
+    }
+
+    if !observation.windows.is_empty() {
+        let titles: Vec<String> = observation
+            .windows
+            .iter()
+            .map(|window| format!("\"{}\"", window.title))
+            .collect();
+        screen_context.push(format!("Open windows: {}.", titles.join(", ")));
+
+        if let Some(focused) = observation
+            .focused_window
+            .and_then(|id| observation.windows.iter().find(|window| window.id == id))
+        {
+            screen_context.push(format!("The focused window is \"{}\".", focused.title));
+        }
+    }
+
+    let window_actions = if observation.windows.is_empty() || observation.region.is_some() {
+        ""
+    } else {
+        WINDOW_ACTIONS
+    };
+
     VISION_PROMPT
+        .replace("{annotation_guide}", annotation_guide)
+        .replace("{window_actions}", window_actions)
+        .replace("{click_format}", click_format)
+        .replace("{click_example}", click_example)
         .replace("{objective}", objective)
         .replace("{previous_action}", &previous_action_formatted)
+        .replace("{screen_context}", &screen_context.join("\n"))
 }
 
 pub fn parse_openai_response(response: &str) -> Result<(String, String), &'static str> {
@@ -53,7 +187,9 @@ pub fn parse_openai_response(response: &str) -> Result<(String, String), &'stati
             "CLICK",
             &['\\'] as &[_],
             true,
-        ),
+        )
+        .or_else(|_| parse_target_click(cleaned_response, r"\d+", "mark"))
+        .or_else(|_| parse_target_click(cleaned_response, r"[A-Za-z]{1,3}\d+", "cell")),
         ActionType::Type => parse_action_response(
             cleaned_response,
             r#"TYPE\s(.+)"#,
@@ -68,6 +204,13 @@ pub fn parse_openai_response(response: &str) -> Result<(String, String), &'stati
             &['\\', '\"'] as &[_],
             false,
         ),
+        ActionType::Window => parse_action_response(
+            cleaned_response,
+            r"WINDOW \{\{(.+)\}\}",
+            "WINDOW",
+            &['\\'] as &[_],
+            true,
+        ),
         ActionType::Unknown => Ok(("UNKNOWN".to_string(), cleaned_response.to_string())),
     }
 }
@@ -96,15 +239,254 @@ fn parse_action_response(
         .ok_or("Regex parsing failed")
 }
 
+/// A click on a mark or cell, `CLICK 17` or `CLICK C4`, optionally followed by
+/// `{{ "description": ..., "reason": ... }}`. The detail is the mark number or the quoted cell
+/// label, or an object with the target under `key` when the fields are given.
+fn parse_target_click(
+    response: &str,
+    target_pattern: &str,
+    key: &str,
+) -> Result<(String, String), &'static str> {
+    let re = Regex::new(&format!(
+        r"CLICK\s+({})(?:\s*\{{\{{(.+)\}}\}})?",
+        target_pattern
+    ))
+    .unwrap();
+    let captures = re.captures(response).ok_or("Regex parsing failed")?;
+    let target = match captures[1].parse::<u64>() {
+        Ok(mark_id) => serde_json::Value::from(mark_id),
+        Err(_) => serde_json::Value::from(&captures[1]),
+    };
+
+    let fields = captures.get(2).and_then(|fields| {
+        convert_string_to_json(&format!("{{{}}}", fields.as_str().trim_matches('\\'))).ok()
+    });
+    let detail = match fields {
+        Some(serde_json::Value::Object(mut fields)) => {
+            fields.insert(key.to_string(), target);
+            serde_json::Value::Object(fields)
+        }
+        _ => target,
+    };
+    Ok(("CLICK".to_string(), detail.to_string()))
+}
+
 pub fn convert_percent_to_decimal(percent_str: &str) -> Result<f32, std::num::ParseFloatError> {
     let percent_str = percent_str.trim_matches(&['"', '%'] as &[_]);
     percent_str.parse::<f32>().map(|num| num / 100.0)
 }
 
+/// Parses a hex color such as `0000ff`, `#0000ff` or `0000ff80` into RGBA.
+pub fn parse_hex_color(s: &str) -> Result<[u8; 4], String> {
+    let hex = s.trim_start_matches('#');
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return Err(format!("Invalid color {}, expected RRGGBB or RRGGBBAA", s));
+    }
+
+    let mut color = [255u8; 4];
+    for (i, channel) in color.iter_mut().enumerate().take(hex.len() / 2) {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|e| format!("Invalid color {}: {}", s, e))?;
+    }
+
+    Ok(color)
+}
+
+/// Position of a percentage click as fractions of the screen.
+pub fn click_detail_position(click_detail: &serde_json::Value) -> Option<(f32, f32)> {
+    let x = convert_percent_to_decimal(click_detail["x"].as_str()?).ok()?;
+    let y = convert_percent_to_decimal(click_detail["y"].as_str()?).ok()?;
+    Some((x, y))
+}
+
+/// Parses a region given as `x,y,width,height` in screen coordinates.
+pub fn parse_region(s: &str) -> Result<Region, String> {
+    let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+    match parts[..] {
+        [x, y, width, height] => {
+            let invalid = |e: std::num::ParseIntError| format!("Invalid region {}: {}", s, e);
+            Ok(Region {
+                x: x.parse().map_err(invalid)?,
+                y: y.parse().map_err(invalid)?,
+                width: width.parse().map_err(invalid)?,
+                height: height.parse().map_err(invalid)?,
+            })
+        }
+        _ => Err(format!("Invalid region {}, expected x,y,width,height", s)),
+    }
+}
+
+/// Parses a display size given as `WIDTHxHEIGHT`, e.g. `1280x800`.
+pub fn parse_display_size(s: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid display size {}, expected WIDTHxHEIGHT", s);
+    let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+    match (width.trim().parse(), height.trim().parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Key names accepted in hotkeys besides letters, digits and F1 to F12.
+const HOTKEY_KEY_NAMES: [&str; 10] = [
+    "ctrl",
+    "shift",
+    "alt",
+    "super",
+    "escape",
+    "pause",
+    "space",
+    "enter",
+    "tab",
+    "backspace",
+];
+
+/// Parses a hotkey such as `ctrl+alt+q` or `shift+escape`.
+pub fn parse_hotkey(s: &str) -> Result<Hotkey, String> {
+    let keys: Vec<String> = s
+        .split('+')
+        .map(|key| match key.trim().to_lowercase().as_str() {
+            "control" => "ctrl".to_string(),
+            "esc" => "escape".to_string(),
+            "return" => "enter".to_string(),
+            "meta" | "win" | "cmd" => "super".to_string(),
+            key => key.to_string(),
+        })
+        .collect();
+    for key in &keys {
+        let is_character = key.len() == 1 && key.chars().all(|c| c.is_ascii_alphanumeric());
+        let is_function_key = key
+            .strip_prefix('f')
+            .and_then(|number| number.parse::<u32>().ok())
+            .is_some_and(|number| (1..=12).contains(&number));
+        if !is_character && !is_function_key && !HOTKEY_KEY_NAMES.contains(&key.as_str()) {
+            return Err(format!("Invalid hotkey {}: unknown key \"{}\"", s, key));
+        }
+    }
+    Ok(Hotkey { keys })
+}
+
+/// Parses a replay override such as `3=SKIP`, `4=TYPE hello` or
+/// `5=CLICK {"x": "50%", "y": "20%"}`.
+pub fn parse_action_override(s: &str) -> Result<(u32, ActionOverride), String> {
+    let (step, action) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid override {}, expected STEP=ACTION", s))?;
+    let step = step
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid step in override {}: {}", s, e))?;
+
+    let action = action.trim();
+    if action == "SKIP" {
+        return Ok((step, ActionOverride::Skip));
+    }
+    let (action, detail) =
+        parse_action_text(action).map_err(|e| format!("Invalid override {}: {}", s, e))?;
+
+    Ok((step, ActionOverride::Replace { action, detail }))
+}
+
+/// Parses an action typed by a person in the form recorded in the trajectory, such as
+/// `TYPE hello` or `CLICK {"x": "50%", "y": "20%"}`.
+pub fn parse_action_text(s: &str) -> Result<(String, String), String> {
+    let s = s.trim();
+    let (action, detail) = s.split_once(' ').unwrap_or((s, ""));
+    match action {
+        "CLICK" | "WINDOW" => {
+            convert_string_to_json(detail)
+                .map_err(|e| format!("Invalid {} detail {}: {}", action, detail, e))?;
+        }
+        "TYPE" | "SEARCH" => {}
+        _ => {
+            return Err(format!(
+                "Unknown action {}, expected CLICK, TYPE, SEARCH or WINDOW",
+                action
+            ))
+        }
+    }
+
+    Ok((action.to_string(), detail.to_string()))
+}
+
 pub fn convert_string_to_json(s: &str) -> Result<serde_json::Value, serde_json::Error> {
     serde_json::from_str(s)
 }
 
+/// Turns a set-of-marks click (`CLICK 17`) or a cell click (`CLICK C4`) into the percentage
+/// click detail used by `mouse_click`, keeping the description and reason the model gave.
+/// Percentage clicks are returned unchanged.
+pub fn resolve_click_detail(
+    click_detail: serde_json::Value,
+    observation: &Observation,
+) -> Result<serde_json::Value, String> {
+    let target = click_detail
+        .get("mark")
+        .or_else(|| click_detail.get("cell"))
+        .unwrap_or(&click_detail);
+    match resolve_click_target(target, observation)? {
+        Some(mut resolved) => {
+            // The description of the model is more telling than the mark or cell, e.g. to
+            // assess risk
+            for key in ["description", "reason"] {
+                if let Some(text) = click_detail.get(key) {
+                    resolved[key] = text.clone();
+                }
+            }
+            Ok(resolved)
+        }
+        None => Ok(click_detail),
+    }
+}
+
+/// The click on a mark number or cell label, `None` when `target` is neither.
+fn resolve_click_target(
+    target: &serde_json::Value,
+    observation: &Observation,
+) -> Result<Option<serde_json::Value>, String> {
+    if let Some(cell) = target.as_str() {
+        let grid_interval = observation
+            .grid
+            .filter(|grid| grid.style == GridStyle::Cells)
+            .map(|grid| grid.interval as u32)
+            .ok_or_else(|| format!("Cell {} was clicked but the screen has no cells", cell))?;
+        let (x, y) = cell_center(cell, grid_interval, observation.width, observation.height)
+            .ok_or_else(|| format!("Cell {} is not on the screen", cell))?;
+        return Ok(Some(serde_json::json!({
+            "x": format!("{:.2}%", x as f32 / observation.width as f32 * 100.0),
+            "y": format!("{:.2}%", y as f32 / observation.height as f32 * 100.0),
+            "description": format!("cell {}", cell),
+            "reason": "",
+        })));
+    }
+
+    let mark_id = match target.as_u64() {
+        Some(mark_id) => mark_id,
+        None => return Ok(None),
+    };
+
+    let mark = observation
+        .marks
+        .iter()
+        .find(|mark| mark.id as u64 == mark_id)
+        .ok_or_else(|| format!("Mark {} is not on the screen", mark_id))?;
+
+    Ok(Some(convert_mark_to_click_detail(
+        mark,
+        observation.width,
+        observation.height,
+    )))
+}
+
+pub fn convert_mark_to_click_detail(mark: &Mark, width: u32, height: u32) -> serde_json::Value {
+    let (x, y) = mark.center();
+    serde_json::json!({
+        "x": format!("{:.2}%", x as f32 / width as f32 * 100.0),
+        "y": format!("{:.2}%", y as f32 / height as f32 * 100.0),
+        "description": format!("mark {}", mark.id),
+        "reason": "",
+    })
+}
+
 pub fn get_last_assistant_message(messages: &mut Vec<Message>) -> String {
     messages
         .iter()
@@ -117,3 +499,165 @@ pub fn get_last_assistant_message(messages: &mut Vec<Message>) -> String {
         })
         .unwrap_or_else(String::new)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::GridOptions;
+    use serde_json::{json, Value};
+
+    fn observation(marks: Vec<Mark>, grid_style: Option<GridStyle>) -> Observation {
+        Observation {
+            screenshot_path: String::new(),
+            annotated_path: String::new(),
+            width: 1000,
+            height: 500,
+            marks,
+            grid: grid_style.map(|style| GridOptions {
+                interval: 100,
+                auto_interval: false,
+                style,
+                color: [0, 0, 255, 255],
+                thickness: 1,
+            }),
+            pointer: None,
+            previous_step: None,
+            region: None,
+            windows: Vec::new(),
+            focused_window: None,
+            redactions: Vec::new(),
+        }
+    }
+
+    fn mark(id: u32) -> Mark {
+        Mark {
+            id,
+            x: 100,
+            y: 50,
+            width: 100,
+            height: 50,
+        }
+    }
+
+    fn detail(response: &str) -> Value {
+        let (action, detail) = parse_openai_response(response).unwrap();
+        assert_eq!(action, "CLICK");
+        convert_string_to_json(&detail).unwrap()
+    }
+
+    #[test]
+    fn parses_mark_and_cell_clicks() {
+        assert_eq!(detail("CLICK 17"), json!(17));
+        assert_eq!(detail("CLICK C4"), json!("C4"));
+        assert_eq!(
+            detail(r#"CLICK 17 {{ "description": "Send button", "reason": "to send" }}"#),
+            json!({"mark": 17, "description": "Send button", "reason": "to send"})
+        );
+        assert_eq!(
+            detail(r#"CLICK AB12 {{ "description": "Name field", "reason": "to fill it" }}"#),
+            json!({"cell": "AB12", "description": "Name field", "reason": "to fill it"})
+        );
+    }
+
+    #[test]
+    fn parses_percentage_clicks() {
+        assert_eq!(
+            detail(r#"CLICK {{ "x": "50%", "y": "20%", "description": "OK", "reason": "" }}"#),
+            json!({"x": "50%", "y": "20%", "description": "OK", "reason": ""})
+        );
+    }
+
+    #[test]
+    fn resolves_a_mark_to_its_center() {
+        let observation = observation(vec![mark(3)], None);
+        let click = resolve_click_detail(json!(3), &observation).unwrap();
+        assert_eq!(click["x"], "15.00%");
+        assert_eq!(click["y"], "15.00%");
+        assert_eq!(click["description"], "mark 3");
+    }
+
+    #[test]
+    fn keeps_the_description_of_the_model() {
+        let observation = observation(vec![mark(3)], None);
+        let click = resolve_click_detail(
+            json!({"mark": 3, "description": "Send button", "reason": "to send"}),
+            &observation,
+        )
+        .unwrap();
+        assert_eq!(click["x"], "15.00%");
+        assert_eq!(click["description"], "Send button");
+        assert_eq!(click["reason"], "to send");
+    }
+
+    #[test]
+    fn resolves_a_cell_to_its_center() {
+        let observation = observation(Vec::new(), Some(GridStyle::Cells));
+        let click = resolve_click_detail(json!({"cell": "B2"}), &observation).unwrap();
+        assert_eq!(click["x"], "15.00%");
+        assert_eq!(click["y"], "30.00%");
+    }
+
+    #[test]
+    fn keeps_percentage_clicks() {
+        let observation = observation(vec![mark(3)], None);
+        let click = json!({"x": "50%", "y": "20%", "description": "OK", "reason": ""});
+        assert_eq!(
+            resolve_click_detail(click.clone(), &observation).unwrap(),
+            click
+        );
+    }
+
+    #[test]
+    fn rejects_targets_that_are_not_on_the_screen() {
+        let marks = observation(vec![mark(3)], None);
+        assert!(resolve_click_detail(json!(4), &marks).is_err());
+        assert!(resolve_click_detail(json!("B2"), &marks).is_err());
+
+        let cells = observation(Vec::new(), Some(GridStyle::Cells));
+        assert!(resolve_click_detail(json!("K1"), &cells).is_err());
+        assert!(resolve_click_detail(json!("A99999999"), &cells).is_err());
+    }
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(
+            parse_region("10, -20,300,200").unwrap(),
+            Region {
+                x: 10,
+                y: -20,
+                width: 300,
+                height: 200,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_regions() {
+        assert!(parse_region("10,20,300").is_err());
+        assert!(parse_region("10,20,300,200,1").is_err());
+        assert!(parse_region("10,20,-300,200").is_err());
+        assert!(parse_region("a,20,300,200").is_err());
+    }
+
+    fn keys(hotkey: &str) -> Vec<String> {
+        parse_hotkey(hotkey).unwrap().keys
+    }
+
+    #[test]
+    fn parses_hotkeys() {
+        assert_eq!(keys("ctrl+alt+q"), ["ctrl", "alt", "q"]);
+        assert_eq!(keys(" Shift + F12 "), ["shift", "f12"]);
+        assert_eq!(keys("control+esc"), ["ctrl", "escape"]);
+        assert_eq!(keys("cmd+return"), ["super", "enter"]);
+        assert_eq!(keys("win+meta+1"), ["super", "super", "1"]);
+    }
+
+    #[test]
+    fn rejects_unknown_hotkey_keys() {
+        assert!(parse_hotkey("ctrl+foo").is_err());
+        assert!(parse_hotkey("f13").is_err());
+        assert!(parse_hotkey("f0").is_err());
+        assert!(parse_hotkey("ctrl+").is_err());
+        assert!(parse_hotkey("ctrl+-").is_err());
+    }
+}
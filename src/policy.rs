@@ -0,0 +1,258 @@
+use crate::{
+    operations::click_position,
+    parsers::ActionType,
+    types::{PolicyRules, Region},
+    window::{list_windows, window_at},
+};
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+
+/// Guardrails checked before every action. Refused actions are not executed and the model is
+/// told why.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    rules: PolicyRules,
+    deny_type_patterns: Vec<Regex>,
+    url_pattern: Regex,
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read policy {}: {}", path, e))?;
+        let rules: PolicyRules = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid policy {}: {}", path, e))?;
+
+        let deny_type_patterns = rules
+            .deny_type_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid pattern {} in policy {}: {}", pattern, path, e))
+            })
+            .collect::<Result<Vec<Regex>, String>>()?;
+
+        Ok(Policy {
+            rules,
+            deny_type_patterns,
+            // A URL with a scheme, or text that is nothing but a domain and a path
+            url_pattern: Regex::new(
+                r"(?i)https?://([^/\s:]+)|^\s*((?:[a-z0-9-]+\.)+[a-z]{2,})(?:[/:]\S*)?\s*$",
+            )
+            .unwrap(),
+        })
+    }
+
+    /// Checks an action before it is executed, returning why it is refused.
+    pub fn check(
+        &self,
+        action: &str,
+        detail: &str,
+        click: Option<&Value>,
+        region: Option<&Region>,
+    ) -> Result<(), String> {
+        if self
+            .rules
+            .deny_actions
+            .iter()
+            .any(|denied| denied == action)
+        {
+            return Err(format!("{} actions are not allowed", action));
+        }
+
+        match ActionType::from_str(action) {
+            ActionType::Type => self.check_text(detail),
+            ActionType::Search => self.check_app(detail),
+            ActionType::Click => match click {
+                Some(click) => self.check_click(click, region),
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn check_text(&self, text: &str) -> Result<(), String> {
+        if let Some(pattern) = self
+            .deny_type_patterns
+            .iter()
+            .find(|pattern| pattern.is_match(text))
+        {
+            return Err(format!("typing text matching {} is not allowed", pattern));
+        }
+
+        if let Some(allowed_domains) = &self.rules.allowed_domains {
+            for captures in self.url_pattern.captures_iter(text) {
+                let domain = captures
+                    .get(1)
+                    .or_else(|| captures.get(2))
+                    .map(|domain| domain.as_str().to_lowercase())
+                    .unwrap_or_default();
+                let allowed = allowed_domains.iter().any(|allowed| {
+                    let allowed = allowed.to_lowercase();
+                    domain == allowed || domain.ends_with(&format!(".{}", allowed))
+                });
+                if !allowed {
+                    return Err(format!("the domain {} is not allowed", domain));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_app(&self, app: &str) -> Result<(), String> {
+        match &self.rules.allowed_apps {
+            Some(allowed_apps)
+                if !allowed_apps
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(app.trim())) =>
+            {
+                Err(format!(
+                    "opening {} is not allowed, only {}",
+                    app,
+                    allowed_apps.join(", ")
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_click(&self, click: &Value, region: Option<&Region>) -> Result<(), String> {
+        let (x, y) = match (click["x"].as_str(), click["y"].as_str()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Ok(()),
+        };
+        // Clicks outside of the allowed region are refused when they are executed
+        let (x, y) = match click_position(x, y, region) {
+            Ok(position) => position,
+            Err(_) => return Ok(()),
+        };
+
+        if self
+            .rules
+            .forbidden_regions
+            .iter()
+            .any(|forbidden| forbidden.contains(x, y))
+        {
+            return Err(format!(
+                "clicking at x: {}, y: {} is not allowed, it is in a forbidden area of the screen",
+                x, y
+            ));
+        }
+
+        if !self.rules.forbidden_windows.is_empty() {
+            // The click goes to the topmost window at its position, even where it covers a
+            // forbidden one
+            let windows = list_windows().unwrap_or_default();
+            if let Some(window) = window_at(&windows, x, y) {
+                let title = window.title.to_lowercase();
+                if self
+                    .rules
+                    .forbidden_windows
+                    .iter()
+                    .any(|forbidden| title.contains(&forbidden.to_lowercase()))
+                {
+                    return Err(format!(
+                        "clicking in the window \"{}\" is not allowed",
+                        window.title
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn load_policy(name: &str, rules: Value) -> Result<Policy, String> {
+        let path = std::env::temp_dir().join(format!(
+            "auto-pilot-policy-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, rules.to_string()).unwrap();
+        let policy = Policy::load(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    #[test]
+    fn refuses_denied_actions() {
+        let policy = load_policy("actions", json!({"deny_actions": ["SEARCH"]})).unwrap();
+        assert!(policy.check("SEARCH", "firefox", None, None).is_err());
+        assert!(policy.check("TYPE", "hello", None, None).is_ok());
+    }
+
+    #[test]
+    fn refuses_denied_text() {
+        let policy = load_policy(
+            "text",
+            json!({"deny_type_patterns": ["rm\\s+-rf", "(?i)password"]}),
+        )
+        .unwrap();
+        assert!(policy.check_text("rm  -rf /").is_err());
+        assert!(policy.check_text("my PASSWORD").is_err());
+        assert!(policy.check_text("hello").is_ok());
+    }
+
+    #[test]
+    fn only_allows_urls_of_allowed_domains() {
+        let policy = load_policy("domains", json!({"allowed_domains": ["example.com"]})).unwrap();
+        assert!(policy.check_text("https://example.com/login").is_ok());
+        assert!(policy.check_text("http://docs.Example.com").is_ok());
+        assert!(policy.check_text("example.com/login").is_ok());
+        assert!(policy.check_text("Hello world.").is_ok());
+        assert!(policy.check_text("https://evil.com").is_err());
+        assert!(policy.check_text("https://notexample.com").is_err());
+        assert!(policy
+            .check_text("see https://example.com and https://evil.com")
+            .is_err());
+        assert!(policy.check_text("evil.org").is_err());
+    }
+
+    #[test]
+    fn only_allows_allowed_apps() {
+        let policy = load_policy("apps", json!({"allowed_apps": ["Firefox"]})).unwrap();
+        assert!(policy.check_app("firefox").is_ok());
+        assert!(policy.check_app(" Firefox ").is_ok());
+        assert!(policy.check_app("terminal").is_err());
+
+        let unrestricted = load_policy("apps-unset", json!({})).unwrap();
+        assert!(unrestricted.check_app("terminal").is_ok());
+    }
+
+    #[test]
+    fn refuses_clicks_in_forbidden_regions() {
+        let policy = load_policy(
+            "regions",
+            json!({"forbidden_regions": [{"x": 100, "y": 100, "width": 50, "height": 50}]}),
+        )
+        .unwrap();
+        let region = Region {
+            x: 0,
+            y: 0,
+            width: 1000,
+            height: 1000,
+        };
+        let inside = json!({"x": "12%", "y": "12%"});
+        let outside = json!({"x": "50%", "y": "50%"});
+        assert!(policy
+            .check("CLICK", "", Some(&inside), Some(&region))
+            .is_err());
+        assert!(policy
+            .check("CLICK", "", Some(&outside), Some(&region))
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        assert!(load_policy("pattern", json!({"deny_type_patterns": ["("]})).is_err());
+        assert!(load_policy("unknown", json!({"deny_everything": true})).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    constants::TRAJECTORY_SCHEMA_VERSION,
+    types::{RefineRecord, Refinement, RunInfo, TrajectoryStep},
+};
+use image::RgbaImage;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Records a run into its own timestamped directory: `run.json` with the objective and
+/// outcome, `trajectory.jsonl` with one line per step and the screenshots of every step.
+pub struct Recorder {
+    pub dir: PathBuf,
+    info: RunInfo,
+    trajectory: File,
+}
+
+impl Recorder {
+    pub fn start(runs_dir: &str, objective: &str) -> Result<Self, String> {
+        fs::create_dir_all(runs_dir)
+            .map_err(|e| format!("Failed to create directory {}: {}", runs_dir, e))?;
+
+        let started_at_ms = unix_millis();
+        let timestamp = format_timestamp(started_at_ms / 1000);
+        // Runs started within the same second get a numbered suffix
+        let dir = (1..100)
+            .map(|attempt| match attempt {
+                1 => Path::new(runs_dir).join(&timestamp),
+                _ => Path::new(runs_dir).join(format!("{}-{}", timestamp, attempt)),
+            })
+            .find(|dir| fs::create_dir(dir).is_ok())
+            .ok_or("Failed to create the run directory")?;
+
+        let trajectory = File::create(dir.join("trajectory.jsonl"))
+            .map_err(|e| format!("Failed to create the trajectory file: {}", e))?;
+
+        let recorder = Recorder {
+            dir,
+            info: RunInfo {
+                schema_version: TRAJECTORY_SCHEMA_VERSION,
+                objective: objective.to_string(),
+                started_at_ms,
+                finished_at_ms: None,
+                outcome: None,
+                summary: None,
+            },
+            trajectory,
+        };
+        recorder.write_info()?;
+
+        Ok(recorder)
+    }
+
+    /// Copies an image of a step into the run directory and returns its relative path.
+    pub fn save_image(&self, step: u32, name: &str, source: &str) -> Result<String, String> {
+        let file_name = format!("step-{:03}-{}.png", step, name);
+        fs::copy(source, self.dir.join(&file_name))
+            .map_err(|e| format!("Failed to record {}: {}", source, e))?;
+        Ok(file_name)
+    }
+
+    /// Saves a captured frame of a step into the run directory and returns its relative path.
+    pub fn save_frame(&self, step: u32, name: &str, frame: &RgbaImage) -> Result<String, String> {
+        let file_name = format!("step-{:03}-{}.png", step, name);
+        frame
+            .save(self.dir.join(&file_name))
+            .map_err(|e| format!("Failed to record the {} of step {}: {}", name, step, e))?;
+        Ok(file_name)
+    }
+
+    pub fn save_refinement(
+        &self,
+        step: u32,
+        refinement: &Refinement,
+    ) -> Result<RefineRecord, String> {
+        Ok(RefineRecord {
+            prompt: refinement.call.prompt.clone(),
+            response: refinement.call.response.clone(),
+            usage: refinement.call.usage,
+            zoom_screenshot: self.save_image(step, "zoom", &refinement.zoom_path)?,
+            zoom_annotated_screenshot: self.save_image(
+                step,
+                "zoom-annotated",
+                &refinement.zoom_annotated_path,
+            )?,
+        })
+    }
+
+    /// Appends a step to the trajectory. Each line is flushed so that a crash keeps the
+    /// steps recorded so far.
+    pub fn record_step(&mut self, step: &TrajectoryStep) -> Result<(), String> {
+        let line = serde_json::to_string(step)
+            .map_err(|e| format!("Failed to serialize step {}: {}", step.step, e))?;
+        writeln!(self.trajectory, "{}", line)
+            .and_then(|_| self.trajectory.flush())
+            .map_err(|e| format!("Failed to write the trajectory: {}", e))
+    }
+
+    pub fn finish(&mut self, outcome: &str, summary: Option<&str>) -> Result<(), String> {
+        self.info.finished_at_ms = Some(unix_millis());
+        self.info.outcome = Some(outcome.to_string());
+        self.info.summary = summary.map(str::to_string);
+        self.write_info()
+    }
+
+    fn write_info(&self) -> Result<(), String> {
+        let info = serde_json::to_string_pretty(&self.info)
+            .map_err(|e| format!("Failed to serialize the run info: {}", e))?;
+        fs::write(self.dir.join("run.json"), info)
+            .map_err(|e| format!("Failed to write the run info: {}", e))
+    }
+}
+
+/// Reads the run info and all steps of a recorded run.
+pub fn load_run(dir: &Path) -> Result<(RunInfo, Vec<TrajectoryStep>), String> {
+    let info = fs::read_to_string(dir.join("run.json"))
+        .map_err(|e| format!("Failed to read {}: {}", dir.join("run.json").display(), e))?;
+    let info: RunInfo =
+        serde_json::from_str(&info).map_err(|e| format!("Invalid run.json: {}", e))?;
+    if info.schema_version != TRAJECTORY_SCHEMA_VERSION {
+        return Err(format!(
+            "Unsupported trajectory schema version {}, expected {}",
+            info.schema_version, TRAJECTORY_SCHEMA_VERSION
+        ));
+    }
+
+    let trajectory = fs::read_to_string(dir.join("trajectory.jsonl"))
+        .map_err(|e| format!("Failed to read the trajectory: {}", e))?;
+    let steps = trajectory
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("Invalid trajectory line {}: {}", i + 1, e))
+        })
+        .collect::<Result<Vec<TrajectoryStep>, String>>()?;
+
+    Ok((info, steps))
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Formats seconds since the epoch as a UTC `YYYY-MM-DD_HH-MM-SS` directory name.
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let seconds_of_day = secs % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
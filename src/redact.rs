@@ -0,0 +1,344 @@
+use crate::{
+    constants::REDACT_BLUR_SIGMA,
+    ocr::{ocr_words, OcrWord},
+    screen::screen_region,
+    types::{Redaction, RedactionStyle, Region},
+    window::list_windows,
+};
+use image::{imageops, Rgba, RgbaImage};
+use imageproc::{drawing::draw_filled_rect_mut, rect::Rect};
+use regex::Regex;
+
+/// Hides parts of every screenshot before it is sent to the model: fixed areas of the screen,
+/// windows by title and, with the `tesseract` command, text matching patterns.
+#[derive(Clone, Debug)]
+pub struct Redactor {
+    regions: Vec<Region>,
+    windows: Vec<String>,
+    text_patterns: Vec<Regex>,
+    style: RedactionStyle,
+}
+
+impl Redactor {
+    pub fn new(
+        regions: Vec<Region>,
+        windows: Vec<String>,
+        text_patterns: &[String],
+        style: RedactionStyle,
+    ) -> Result<Self, String> {
+        let text_patterns = text_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern)
+                    .map_err(|e| format!("Invalid redaction pattern {}: {}", pattern, e))
+            })
+            .collect::<Result<Vec<Regex>, String>>()?;
+        Ok(Redactor {
+            regions,
+            windows,
+            text_patterns,
+            style,
+        })
+    }
+
+    /// Whether windows with this title are hidden, so their title must not be sent either.
+    pub fn hides_window(&self, title: &str) -> bool {
+        let title = title.to_lowercase();
+        self.windows
+            .iter()
+            .any(|hidden| title.contains(&hidden.to_lowercase()))
+    }
+
+    /// Redacts the screenshot at `path` in place. `capture_area` is the part of the screen
+    /// the screenshot shows, the whole screen when `None`. Returns the hidden areas.
+    pub fn redact(
+        &self,
+        path: &str,
+        capture_area: Option<&Region>,
+    ) -> Result<Vec<Redaction>, String> {
+        let mut screenshot = image::open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?
+            .into_rgba8();
+        let capture_area = match capture_area {
+            Some(area) => *area,
+            None => screen_region()?,
+        };
+        // Screenshots are in physical pixels, regions and windows in screen coordinates
+        let scale = screenshot.width() as f32 / capture_area.width as f32;
+        let mut redactions: Vec<Redaction> = self
+            .regions
+            .iter()
+            .map(|region| to_pixels(region, &capture_area, scale, "region".to_string()))
+            .collect();
+        if !self.windows.is_empty() {
+            let windows =
+                list_windows().map_err(|e| format!("Failed to find windows to redact: {}", e))?;
+            for title in &self.windows {
+                let title_lowercase = title.to_lowercase();
+                redactions.extend(
+                    windows
+                        .iter()
+                        .filter(|window| window.title.to_lowercase().contains(&title_lowercase))
+                        .map(|window| {
+                            to_pixels(
+                                &window.region,
+                                &capture_area,
+                                scale,
+                                format!("window \"{}\"", title),
+                            )
+                        }),
+                );
+            }
+        }
+        if !self.text_patterns.is_empty() {
+            redactions.extend(self.find_text(path)?);
+        }
+
+        // Only keep the parts that are on the screenshot
+        let redactions: Vec<Redaction> = redactions
+            .into_iter()
+            .filter_map(|redaction| clip(redaction, screenshot.width(), screenshot.height()))
+            .collect();
+        if redactions.is_empty() {
+            return Ok(redactions);
+        }
+
+        for redaction in &redactions {
+            self.hide(&mut screenshot, redaction);
+        }
+        screenshot
+            .save(path)
+            .map_err(|e| format!("Failed to save the redacted screenshot: {}", e))?;
+
+        Ok(redactions)
+    }
+
+    fn hide(&self, screenshot: &mut RgbaImage, redaction: &Redaction) {
+        match self.style {
+            RedactionStyle::Black => draw_filled_rect_mut(
+                screenshot,
+                Rect::at(redaction.x as i32, redaction.y as i32)
+                    .of_size(redaction.width, redaction.height),
+                Rgba([0, 0, 0, 255]),
+            ),
+            RedactionStyle::Blur => {
+                let area = imageops::crop_imm(
+                    screenshot,
+                    redaction.x,
+                    redaction.y,
+                    redaction.width,
+                    redaction.height,
+                )
+                .to_image();
+                let blurred = imageops::blur(&area, REDACT_BLUR_SIGMA);
+                imageops::replace(screenshot, &blurred, redaction.x as i64, redaction.y as i64);
+            }
+        }
+    }
+
+    /// Areas of text matching one of the patterns. Patterns are matched against whole lines,
+    /// so they can span several words.
+    fn find_text(&self, path: &str) -> Result<Vec<Redaction>, String> {
+        let words = ocr_words(path)?;
+        let mut redactions = Vec::new();
+
+        let mut start = 0;
+        while start < words.len() {
+            let end = start
+                + words[start..]
+                    .iter()
+                    .take_while(|word| word.line == words[start].line)
+                    .count();
+            let line = &words[start..end];
+
+            // Byte range of every word in the line text
+            let mut text = String::new();
+            let mut spans = Vec::new();
+            for word in line {
+                if !text.is_empty() {
+                    text.push(' ');
+                }
+                spans.push((text.len(), text.len() + word.text.len()));
+                text.push_str(&word.text);
+            }
+
+            for pattern in &self.text_patterns {
+                for found in pattern.find_iter(&text) {
+                    let covered: Vec<&OcrWord> = line
+                        .iter()
+                        .zip(&spans)
+                        .filter(|(_, (word_start, word_end))| {
+                            *word_start < found.end() && found.start() < *word_end
+                        })
+                        .map(|(word, _)| word)
+                        .collect();
+                    if let Some(area) = bounding_box(&covered) {
+                        redactions.push(Redaction {
+                            reason: format!("text matching {}", pattern),
+                            ..area
+                        });
+                    }
+                }
+            }
+            start = end;
+        }
+
+        Ok(redactions)
+    }
+}
+
+/// Fills the redacted areas of an image in black, e.g. of a frame compared to a redacted one.
+pub fn black_out(image: &mut RgbaImage, redactions: &[Redaction]) {
+    for redaction in redactions {
+        if let Some(area) = clip(redaction.clone(), image.width(), image.height()) {
+            draw_filled_rect_mut(
+                image,
+                Rect::at(area.x as i32, area.y as i32).of_size(area.width, area.height),
+                Rgba([0, 0, 0, 255]),
+            );
+        }
+    }
+}
+
+fn bounding_box(words: &[&OcrWord]) -> Option<Redaction> {
+    let left = words.iter().map(|word| word.x).min()?;
+    let top = words.iter().map(|word| word.y).min()?;
+    let right = words.iter().map(|word| word.x + word.width).max()?;
+    let bottom = words.iter().map(|word| word.y + word.height).max()?;
+    Some(Redaction {
+        reason: String::new(),
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+/// The pixels of the screenshot of `capture_area` that show `region`, with `scale` physical
+/// pixels per screen coordinate. Parts before the screenshot are cut off.
+fn to_pixels(region: &Region, capture_area: &Region, scale: f32, reason: String) -> Redaction {
+    let left = (region.x - capture_area.x) as f32;
+    let top = (region.y - capture_area.y) as f32;
+    let pixel = |coordinate: f32| (coordinate * scale).round().max(0.0) as u32;
+    let (x, y) = (pixel(left), pixel(top));
+    Redaction {
+        reason,
+        x,
+        y,
+        width: pixel(left + region.width as f32).saturating_sub(x),
+        height: pixel(top + region.height as f32).saturating_sub(y),
+    }
+}
+
+fn clip(redaction: Redaction, width: u32, height: u32) -> Option<Redaction> {
+    let right = redaction.x.saturating_add(redaction.width).min(width);
+    let bottom = redaction.y.saturating_add(redaction.height).min(height);
+    if redaction.x >= right || redaction.y >= bottom {
+        return None;
+    }
+    Some(Redaction {
+        width: right - redaction.x,
+        height: bottom - redaction.y,
+        ..redaction
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn redaction(x: u32, y: u32, width: u32, height: u32) -> Redaction {
+        Redaction {
+            reason: "region".to_string(),
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn converts_screen_coordinates_to_pixels() {
+        let screen = region(0, 0, 1000, 500);
+        assert_eq!(
+            to_pixels(
+                &region(100, 50, 200, 100),
+                &screen,
+                1.0,
+                "region".to_string()
+            ),
+            redaction(100, 50, 200, 100)
+        );
+        assert_eq!(
+            to_pixels(
+                &region(100, 50, 200, 100),
+                &screen,
+                2.0,
+                "region".to_string()
+            ),
+            redaction(200, 100, 400, 200)
+        );
+
+        let capture_area = region(-500, 100, 400, 300);
+        assert_eq!(
+            to_pixels(
+                &region(-400, 150, 10, 10),
+                &capture_area,
+                1.5,
+                "region".to_string()
+            ),
+            redaction(150, 75, 15, 15)
+        );
+    }
+
+    #[test]
+    fn cuts_off_regions_before_the_screenshot() {
+        let capture_area = region(100, 100, 400, 300);
+        assert_eq!(
+            to_pixels(
+                &region(50, 80, 100, 100),
+                &capture_area,
+                1.0,
+                "region".to_string()
+            ),
+            redaction(0, 0, 50, 80)
+        );
+        assert_eq!(
+            to_pixels(
+                &region(0, 0, 50, 50),
+                &capture_area,
+                1.0,
+                "region".to_string()
+            )
+            .width,
+            0
+        );
+    }
+
+    #[test]
+    fn clips_redactions_to_the_screenshot() {
+        assert_eq!(
+            clip(redaction(10, 20, 30, 40), 100, 100),
+            Some(redaction(10, 20, 30, 40))
+        );
+        assert_eq!(
+            clip(redaction(80, 90, 30, 40), 100, 100),
+            Some(redaction(80, 90, 20, 10))
+        );
+        assert_eq!(
+            clip(redaction(10, 10, u32::MAX, u32::MAX), 100, 100),
+            Some(redaction(10, 10, 90, 90))
+        );
+        assert_eq!(clip(redaction(100, 10, 30, 40), 100, 100), None);
+        assert_eq!(clip(redaction(10, 10, 0, 40), 100, 100), None);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    action::send_message_to_openai,
+    constants::{MAX_TOKENS, REFINE_GRID_INTERVAL},
+    parsers::{
+        convert_percent_to_decimal, convert_string_to_json, format_refine_prompt,
+        parse_openai_response, ActionType,
+    },
+    screen::{add_grid_to_image, crop_and_zoom},
+    types::{
+        Config, GridOptions, GridStyle, ImageMessage, ImageMessageContent, ImageUrl, Message,
+        ModelCall, Observation, OpenAIRequest, Refinement, Role,
+    },
+};
+use base64::{engine::general_purpose, Engine as _};
+use colored::Colorize;
+use serde_json::Value;
+use std::{fs, time::Instant};
+
+/// Second pass of a click: shows the model a zoomed-in crop around the coarse target
+/// and replaces the click coordinates with the refined ones. Returns `None` for clicks
+/// that have no coordinates to refine.
+pub async fn refine_click(
+    objective: &str,
+    click_detail: Value,
+    observation: &Observation,
+    config: &Config,
+) -> Result<Option<Refinement>, String> {
+    let (x_percent, y_percent) = match (click_detail["x"].as_str(), click_detail["y"].as_str()) {
+        (Some(x), Some(y)) => (x.to_string(), y.to_string()),
+        _ => return Ok(None),
+    };
+    let x_decimal = convert_percent_to_decimal(&x_percent)
+        .map_err(|e| format!("Invalid x percentage {}: {}", x_percent, e))?;
+    let y_decimal = convert_percent_to_decimal(&y_percent)
+        .map_err(|e| format!("Invalid y percentage {}: {}", y_percent, e))?;
+
+    let center = (
+        (x_decimal * observation.width as f32).round() as u32,
+        (y_decimal * observation.height as f32).round() as u32,
+    );
+
+    let zoom_filename = "screenshots/screenshot_zoom.png";
+    let (crop_x, crop_y, crop_width, crop_height) = crop_and_zoom(
+        &observation.screenshot_path,
+        zoom_filename,
+        center,
+        config.zoom_crop_size,
+        config.zoom_factor,
+    )
+    .map_err(|e| format!("Error zooming into screenshot: {}", e))?;
+
+    let zoom_grid_filename = "screenshots/screenshot_zoom_with_grid.png";
+    // The refine prompt asks for percentages, so the zoomed image always uses labels
+    let grid = GridOptions {
+        interval: REFINE_GRID_INTERVAL,
+        style: GridStyle::Labels,
+        ..config.grid
+    };
+    add_grid_to_image(zoom_filename, zoom_grid_filename, &grid)
+        .map_err(|e| format!("Error adding grid to zoomed image: {}", e))?;
+
+    println!(
+        "{} {} coarse: {} at ({}, {}), zoomed: {}",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Refine]".bright_magenta(),
+        observation.annotated_path,
+        center.0,
+        center.1,
+        zoom_grid_filename
+    );
+
+    let img_file = fs::read(zoom_grid_filename)
+        .map_err(|e| format!("Error reading zoomed screenshot file: {}", e))?;
+    let img_base64 = general_purpose::STANDARD.encode(&img_file);
+
+    let description = click_detail["description"].as_str().unwrap_or_default();
+    let refine_prompt = format_refine_prompt(objective, description);
+    let refine_message = Message::ImageMessage(ImageMessage {
+        role: Role::User,
+        content: vec![
+            ImageMessageContent::Text {
+                text: refine_prompt.clone(),
+            },
+            ImageMessageContent::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:image/jpeg;base64,{}", img_base64),
+                },
+            },
+        ],
+    });
+
+    let payload = OpenAIRequest {
+        model: "gpt-4-vision-preview".to_string(),
+        messages: vec![refine_message],
+        max_tokens: MAX_TOKENS,
+    };
+
+    let request_start = Instant::now();
+    let (raw_content, usage) = send_message_to_openai(payload)
+        .await
+        .map_err(|e| format!("Error sending message to OpenAI: {}", e))?;
+    let call = ModelCall {
+        prompt: refine_prompt,
+        response: raw_content.clone(),
+        duration_ms: request_start.elapsed().as_millis() as u64,
+        usage,
+    };
+    let content = raw_content.replace('\\', "");
+
+    let (action_type, action_detail) = parse_openai_response(&content)?;
+    if !matches!(ActionType::from_str(&action_type), ActionType::Click) {
+        return Err(format!("Expected a CLICK while refining, got: {}", content));
+    }
+    let refined = convert_string_to_json(&action_detail)
+        .map_err(|e| format!("Invalid refined click {}: {}", action_detail, e))?;
+
+    let refined_x = refined["x"].as_str().map(convert_percent_to_decimal);
+    let refined_y = refined["y"].as_str().map(convert_percent_to_decimal);
+    let (refined_x, refined_y) = match (refined_x, refined_y) {
+        (Some(Ok(x)), Some(Ok(y))) => (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0)),
+        _ => return Err(format!("Invalid refined click: {}", action_detail)),
+    };
+
+    // Map the position inside the crop back to a percentage of the whole screenshot
+    let absolute_x = crop_x as f32 + refined_x * crop_width as f32;
+    let absolute_y = crop_y as f32 + refined_y * crop_height as f32;
+
+    let mut click_detail = click_detail;
+    click_detail["x"] = Value::String(format!(
+        "{:.2}%",
+        absolute_x / observation.width as f32 * 100.0
+    ));
+    click_detail["y"] = Value::String(format!(
+        "{:.2}%",
+        absolute_y / observation.height as f32 * 100.0
+    ));
+
+    Ok(Some(Refinement {
+        click_detail,
+        call,
+        zoom_path: zoom_filename.to_string(),
+        zoom_annotated_path: zoom_grid_filename.to_string(),
+    }))
+}
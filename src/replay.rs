@@ -0,0 +1,240 @@
+use crate::{
+    audit::{input_target, AuditEvent, AuditLog},
+    emergency::abort_reason,
+    operations::{keyboard_type, manage_window, mouse_click, release_all_input, search},
+    parsers::{convert_string_to_json, ActionType},
+    recorder::load_run,
+    redact::black_out,
+    screen::{capture_frame, frame_difference, wait_for_stable_screen},
+    secrets::Secrets,
+    terminal::read_line,
+    types::{ActionOverride, ActionResult, Region, ReplayOptions, TrajectoryStep},
+};
+use colored::Colorize;
+use serde_json::Value;
+use std::path::Path;
+
+/// Re-executes the actions of a recorded run on the current screen without calling the
+/// model. Clicks use the recorded positions after marks, cells and refinement were resolved.
+/// Executed actions are appended to `audit_log`.
+pub async fn replay(
+    run_dir: &Path,
+    options: &ReplayOptions,
+    mut audit_log: Option<&mut AuditLog>,
+) -> Result<(), String> {
+    let (info, steps) = load_run(run_dir)?;
+    println!(
+        "{} {} {} steps of \"{}\"",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Replay]".bright_magenta(),
+        steps.len(),
+        info.objective
+    );
+
+    for step in &steps {
+        if let Some(reason) = abort_reason() {
+            release_all_input();
+            return Err(format!("Replay stopped at step {}: {}", step.step, reason));
+        }
+
+        let action_override = options
+            .overrides
+            .iter()
+            .rev()
+            .find(|(number, _)| *number == step.step)
+            .map(|(_, action_override)| action_override);
+        let (action, detail) = match action_override {
+            Some(ActionOverride::Skip) => {
+                print_step(step.step, "SKIP", "overridden");
+                continue;
+            }
+            Some(ActionOverride::Replace { action, detail }) => (action.as_str(), detail.as_str()),
+            None => (step.action.as_str(), step.action_detail.as_str()),
+        };
+
+        if matches!(ActionType::from_str(action), ActionType::Done) {
+            print_step(step.step, action, "end of the recording");
+            break;
+        }
+        // Dry runs, skipped and refused actions must stay without effect, unless replaced
+        if action_override.is_none() && !step.executed {
+            print_step(
+                step.step,
+                "SKIP",
+                &format!("not executed in the recording: {}", step.result),
+            );
+            continue;
+        }
+
+        if let Some(max_divergence) = options.max_divergence {
+            let divergence = screen_divergence(run_dir, step)?;
+            if divergence > max_divergence {
+                return Err(format!(
+                    "Step {} diverged: {:.1}% of the screen differs from the recording (at most {:.1}% allowed)",
+                    step.step,
+                    divergence * 100.0,
+                    max_divergence * 100.0
+                ));
+            }
+        }
+
+        if options.step_manually {
+            match ask(step.step, action, detail).await? {
+                StepChoice::Run => {}
+                StepChoice::Skip => continue,
+                StepChoice::Quit => break,
+            }
+        }
+
+        // Overridden clicks carry their position in the detail
+        let click = match (action_override, ActionType::from_str(action)) {
+            (Some(_), ActionType::Click) => convert_string_to_json(detail).ok(),
+            (Some(_), _) => None,
+            (None, _) => step.click.clone(),
+        };
+        if let Some(policy) = &options.policy {
+            if let Err(violation) =
+                policy.check(action, detail, click.as_ref(), step.region.as_ref())
+            {
+                print_step(
+                    step.step,
+                    "SKIP",
+                    &format!("refused by policy: {}", violation),
+                );
+                continue;
+            }
+        }
+
+        let before_action = capture_frame(step.region.as_ref()).ok();
+        // Looked up before acting, since the action can move the focus
+        let target = audit_log
+            .is_some()
+            .then(|| input_target(click.as_ref(), step.region.as_ref()));
+        let result = execute(
+            action,
+            detail,
+            click,
+            step.region.as_ref(),
+            &options.secrets,
+        )?;
+        print_step(step.step, action, &result.message);
+
+        if let (true, Some(audit_log), Some(target)) = (result.executed, audit_log.as_mut(), target)
+        {
+            let screenshot_path = run_dir.join(&step.screenshot);
+            audit_log.record(&AuditEvent {
+                action,
+                detail,
+                target,
+                screenshot_path: &screenshot_path.to_string_lossy(),
+                response: None,
+                result: &result.message,
+            })?;
+        }
+
+        wait_for_stable_screen(
+            before_action.as_ref(),
+            &options.stability,
+            step.region.as_ref(),
+        )
+        .await?;
+    }
+
+    if let Some(reason) = abort_reason() {
+        release_all_input();
+        return Err(format!("Replay stopped: {}", reason));
+    }
+
+    Ok(())
+}
+
+/// Runs one action in the form recorded in the trajectory. `click` is the resolved click of
+/// a CLICK, when it is missing the position is read from `detail`.
+fn execute(
+    action: &str,
+    detail: &str,
+    click: Option<Value>,
+    region: Option<&Region>,
+    secrets: &Secrets,
+) -> Result<ActionResult, String> {
+    let result = match ActionType::from_str(action) {
+        ActionType::Click => {
+            let click_detail = match click {
+                Some(click_detail) => click_detail,
+                None => convert_string_to_json(detail)
+                    .map_err(|e| format!("Invalid click {}: {}", detail, e))?,
+            };
+            mouse_click(&click_detail, region)
+        }
+        ActionType::Type => keyboard_type(detail, secrets),
+        ActionType::Search if region.is_some() => ActionResult::not_executed(
+            "Refused: SEARCH is disabled while the agent is limited to a region".to_string(),
+        ),
+        ActionType::Search => search(detail),
+        ActionType::Window if region.is_some() => ActionResult::not_executed(
+            "Refused: WINDOW is disabled while the agent is limited to a region".to_string(),
+        ),
+        ActionType::Window => {
+            let window_detail = convert_string_to_json(detail)
+                .map_err(|e| format!("Invalid window action {}: {}", detail, e))?;
+            manage_window(&window_detail)
+        }
+        ActionType::Done | ActionType::Unknown => {
+            ActionResult::not_executed(format!("Skipped {}", action))
+        }
+    };
+
+    Ok(result)
+}
+
+/// Fraction of the screen that differs from the frame recorded for `step`. Recordings
+/// without frames are compared to the screenshot, which older versions drew the cursor on.
+fn screen_divergence(run_dir: &Path, step: &TrajectoryStep) -> Result<f32, String> {
+    let recorded_path = step.frame.as_ref().unwrap_or(&step.screenshot);
+    let recorded = image::open(run_dir.join(recorded_path))
+        .map_err(|e| format!("Failed to open {}: {}", recorded_path, e))?
+        .to_rgba8();
+    let mut current = capture_frame(step.region.as_ref())?;
+    // Areas hidden on the recording can't be compared
+    black_out(&mut current, &step.redactions);
+    Ok(frame_difference(&recorded, &current))
+}
+
+enum StepChoice {
+    Run,
+    Skip,
+    Quit,
+}
+
+async fn ask(step: u32, action: &str, detail: &str) -> Result<StepChoice, String> {
+    loop {
+        println!(
+            "{} Step {}: {} {}\nPress Enter to run it, s to skip it or q to stop",
+            "[Replay]".bright_magenta(),
+            step,
+            action,
+            detail
+        );
+        let answer = match read_line().await? {
+            Some(answer) => answer,
+            None => return Ok(StepChoice::Quit),
+        };
+        match answer.trim() {
+            "" => return Ok(StepChoice::Run),
+            "s" => return Ok(StepChoice::Skip),
+            "q" => return Ok(StepChoice::Quit),
+            _ => {}
+        }
+    }
+}
+
+fn print_step(step: u32, action: &str, result: &str) {
+    println!(
+        "{} {} {} {} {}",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Replay]".bright_magenta(),
+        step,
+        action,
+        result
+    );
+}
@@ -0,0 +1,244 @@
+use crate::{
+    parsers::{click_detail_position, convert_string_to_json},
+    recorder::load_run,
+    types::{Confirmation, RunInfo, StepTimings, TokenUsage, TrajectoryStep},
+};
+use base64::{engine::general_purpose, Engine as _};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const REPORT_STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em auto; max-width: 1400px; color: #222; }
+.step { border-top: 1px solid #ccc; padding: 1em 0; }
+.images { display: flex; gap: 1em; align-items: flex-start; }
+.images figure { flex: 1; margin: 0; }
+.images img { width: 100%; border: 1px solid #ccc; }
+.overlay { position: relative; }
+.click { position: absolute; width: 24px; height: 24px; margin: -14px 0 0 -14px; border: 3px solid #0c0; border-radius: 50%; }
+.action { font-family: monospace; font-size: 1.1em; background: #f4f4f4; padding: 0.3em 0.5em; }
+.result { color: #555; }
+table { border-collapse: collapse; }
+td, th { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: right; }
+pre { white-space: pre-wrap; background: #f8f8f8; padding: 0.5em; }
+"#;
+
+/// Renders a recorded run into a single HTML file with the screenshots embedded, so it can
+/// be shared as is. Returns the path of the report.
+pub fn write_report(run_dir: &Path, output: Option<&Path>) -> Result<PathBuf, String> {
+    let (info, steps) = load_run(run_dir)?;
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!(
+        "<title>Auto Pilot run: {}</title>\n<style>{}</style>\n</head>\n<body>\n",
+        escape_html(&info.objective),
+        REPORT_STYLE
+    ));
+    html.push_str(&render_header(&info, &steps));
+    for step in &steps {
+        html.push_str(&render_step(run_dir, step)?);
+    }
+    html.push_str("</body>\n</html>\n");
+
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| run_dir.join("report.html"));
+    fs::write(&output, html).map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+
+    Ok(output)
+}
+
+fn render_header(info: &RunInfo, steps: &[TrajectoryStep]) -> String {
+    let duration = info
+        .finished_at_ms
+        .map(|finished| {
+            format!(
+                "{:.1} s",
+                finished.saturating_sub(info.started_at_ms) as f32 / 1000.0
+            )
+        })
+        .unwrap_or_else(|| "unfinished".to_string());
+    let usage = steps
+        .iter()
+        .flat_map(|step| {
+            [
+                step.usage,
+                step.refine.as_ref().and_then(|refine| refine.usage),
+            ]
+        })
+        .flatten()
+        .fold(TokenUsage::default(), |total, usage| TokenUsage {
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+            total_tokens: total.total_tokens + usage.total_tokens,
+            image_tokens: total.image_tokens + usage.image_tokens,
+            cost_usd: match (total.cost_usd, usage.cost_usd) {
+                (Some(total), Some(cost)) => Some(total + cost),
+                (total, cost) => total.or(cost),
+            },
+        });
+
+    let mut html = format!(
+        "<h1>{}</h1>\n<p>Outcome: <b>{}</b>, {} steps, {}, {} tokens ({} prompt including {} for images, {} completion){}</p>\n",
+        escape_html(&info.objective),
+        escape_html(info.outcome.as_deref().unwrap_or("unknown")),
+        steps.len(),
+        duration,
+        usage.total_tokens,
+        usage.prompt_tokens,
+        usage.image_tokens,
+        usage.completion_tokens,
+        format_cost(usage.cost_usd)
+    );
+
+    if let Some(summary) = steps.iter().rev().find(|step| step.action == "DONE") {
+        html.push_str(&format!(
+            "<h2>Summary</h2>\n<p>{}</p>\n",
+            escape_html(&summary.result)
+        ));
+    } else if let Some(summary) = &info.summary {
+        html.push_str(&format!(
+            "<h2>Progress when stopped</h2>\n<p>{}</p>\n",
+            escape_html(summary)
+        ));
+    }
+
+    html
+}
+
+fn render_step(run_dir: &Path, step: &TrajectoryStep) -> Result<String, String> {
+    let mut html = format!(
+        "<div class=\"step\">\n<h2>Step {}</h2>\n<p class=\"action\">{} {}</p>\n",
+        step.step,
+        escape_html(&step.action),
+        escape_html(&step.action_detail)
+    );
+
+    if let Some(reason) = reasoning(step) {
+        html.push_str(&format!("<p>{}</p>\n", escape_html(&reason)));
+    }
+    if let Some(risk) = step.risk.as_ref().filter(|risk| !risk.reasons.is_empty()) {
+        html.push_str(&format!(
+            "<p>Risk: {:?}, {}</p>\n",
+            risk.level,
+            escape_html(&risk.reasons.join(", "))
+        ));
+    }
+    if let Some(confirmation) = &step.confirmation {
+        let choice = match confirmation {
+            Confirmation::Approved => "approved".to_string(),
+            Confirmation::Skipped => "skipped".to_string(),
+            Confirmation::Edited {
+                original_action,
+                original_detail,
+            } => format!(
+                "edited, the model asked for {} {}",
+                original_action, original_detail
+            ),
+            Confirmation::Aborted => "aborted".to_string(),
+        };
+        html.push_str(&format!("<p>User: {}</p>\n", escape_html(&choice)));
+    }
+    html.push_str(&format!(
+        "<p class=\"result\">Result: {}</p>\n",
+        escape_html(&step.result)
+    ));
+
+    // Clicks are drawn on the raw screenshot since the annotated one may have margins
+    let click_marker = step
+        .click
+        .as_ref()
+        .and_then(click_detail_position)
+        .map(|(x, y)| {
+            format!(
+                "<div class=\"click\" style=\"left: {:.2}%; top: {:.2}%\"></div>",
+                x * 100.0,
+                y * 100.0
+            )
+        })
+        .unwrap_or_default();
+    html.push_str(&format!(
+        "<div class=\"images\">\n<figure><img src=\"{}\"><figcaption>What the model saw</figcaption></figure>\n<figure><div class=\"overlay\"><img src=\"{}\">{}</div><figcaption>Screen and click location</figcaption></figure>\n</div>\n",
+        embed_image(run_dir, &step.annotated_screenshot)?,
+        embed_image(run_dir, &step.screenshot)?,
+        click_marker
+    ));
+
+    html.push_str(&render_timings(&step.timings, step.usage));
+
+    html.push_str(&format!(
+        "<details><summary>Prompt</summary><pre>{}</pre></details>\n<details><summary>Raw response</summary><pre>{}</pre></details>\n",
+        escape_html(&step.prompt),
+        escape_html(&step.response)
+    ));
+
+    if let Some(refine) = &step.refine {
+        html.push_str(&format!(
+            "<details><summary>Refinement</summary>\n<div class=\"images\"><figure><img src=\"{}\"><figcaption>Zoomed screenshot</figcaption></figure></div>\n<pre>{}</pre>\n<pre>{}</pre>\n</details>\n",
+            embed_image(run_dir, &refine.zoom_annotated_screenshot)?,
+            escape_html(&refine.prompt),
+            escape_html(&refine.response)
+        ));
+    }
+
+    html.push_str("</div>\n");
+    Ok(html)
+}
+
+fn render_timings(timings: &StepTimings, usage: Option<TokenUsage>) -> String {
+    let tokens = usage
+        .map(|usage| {
+            format!(
+                "{} ({} + {}){}",
+                usage.total_tokens,
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                format_cost(usage.cost_usd)
+            )
+        })
+        .unwrap_or_else(|| "-".to_string());
+    format!(
+        "<table><tr><th>Observe</th><th>Model</th><th>Refine</th><th>Act</th><th>Settle</th><th>Tokens</th></tr><tr><td>{} ms</td><td>{} ms</td><td>{} ms</td><td>{} ms</td><td>{} ms</td><td>{}</td></tr></table>\n",
+        timings.observe_ms,
+        timings.model_ms,
+        timings.refine_ms,
+        timings.act_ms,
+        timings.settle_ms,
+        tokens
+    )
+}
+
+fn format_cost(cost_usd: Option<f64>) -> String {
+    cost_usd
+        .map(|cost_usd| format!(", ${:.4}", cost_usd))
+        .unwrap_or_default()
+}
+
+/// The `reason` and `description` the model gave for its action, if any.
+fn reasoning(step: &TrajectoryStep) -> Option<String> {
+    let detail = convert_string_to_json(&step.action_detail).ok()?;
+    let parts: Vec<&str> = ["description", "reason"]
+        .iter()
+        .filter_map(|key| detail[key].as_str())
+        .filter(|text| !text.is_empty())
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(": "))
+}
+
+fn embed_image(run_dir: &Path, file_name: &str) -> Result<String, String> {
+    let image = fs::read(run_dir.join(file_name))
+        .map_err(|e| format!("Failed to read {}: {}", file_name, e))?;
+    Ok(format!(
+        "data:image/png;base64,{}",
+        general_purpose::STANDARD.encode(image)
+    ))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
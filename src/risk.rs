@@ -0,0 +1,172 @@
+use crate::{
+    constants::{
+        HIGH_RISK_TYPE_PATTERNS, HIGH_RISK_WORDS, MEDIUM_RISK_WORDS, RISK_OCR_CROP_SIZE,
+        RISK_OCR_RADIUS, RISK_OCR_ZOOM,
+    },
+    ocr::ocr_words,
+    parsers::{click_detail_position, convert_string_to_json, ActionType},
+    screen::crop_and_zoom,
+    types::{Observation, RiskAssessment, RiskLevel},
+};
+use regex::Regex;
+use serde_json::Value;
+
+/// Scores how destructive an action looks from what the model said about its click, the text
+/// it types and, with `ocr`, the text on the screen around the click. A click target that
+/// can't be read is at least medium risk, so it is still confirmed.
+pub fn assess_risk(
+    action: &str,
+    detail: &str,
+    click: Option<&Value>,
+    observation: &Observation,
+    ocr: bool,
+) -> RiskAssessment {
+    let mut assessment = RiskAssessment {
+        level: RiskLevel::Low,
+        reasons: Vec::new(),
+    };
+    let mut flag = |level: RiskLevel, reason: String| {
+        assessment.level = assessment.level.max(level);
+        // The resolved click usually repeats the fields of the detail
+        if !assessment.reasons.contains(&reason) {
+            assessment.reasons.push(reason);
+        }
+    };
+
+    match ActionType::from_str(action) {
+        ActionType::Click => {
+            let detail = convert_string_to_json(detail).unwrap_or_default();
+            for source in [click, Some(&detail)].into_iter().flatten() {
+                for key in ["description", "reason"] {
+                    if let Some(text) = source[key].as_str() {
+                        if let Some((level, word)) = risky_word(text) {
+                            flag(level, format!("the click {} mentions \"{}\"", key, word));
+                        }
+                    }
+                }
+            }
+
+            if let (true, Some((x, y))) = (ocr, click.and_then(click_detail_position)) {
+                match text_near(observation, x, y) {
+                    Ok(text) => {
+                        if let Some((level, _)) = risky_word(&text) {
+                            flag(level, format!("the text at the click reads \"{}\"", text));
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to read the click target: {}", e);
+                        flag(
+                            RiskLevel::Medium,
+                            "the text at the click could not be read".to_string(),
+                        );
+                    }
+                }
+            }
+        }
+        ActionType::Type => {
+            for pattern in HIGH_RISK_TYPE_PATTERNS {
+                if Regex::new(pattern).unwrap().is_match(detail) {
+                    flag(
+                        RiskLevel::High,
+                        format!("the typed text matches {}", pattern),
+                    );
+                }
+            }
+        }
+        ActionType::Window => {
+            let detail = convert_string_to_json(detail).unwrap_or_default();
+            if detail["action"].as_str() == Some("close") {
+                flag(RiskLevel::Medium, "closing a window".to_string());
+            }
+        }
+        ActionType::Search | ActionType::Done | ActionType::Unknown => {}
+    }
+
+    assessment
+}
+
+/// The riskiest word of the lists found in `text`.
+fn risky_word(text: &str) -> Option<(RiskLevel, &'static str)> {
+    let contains = |word: &str| {
+        Regex::new(&format!(r"(?i)\b{}\b", regex::escape(word)))
+            .unwrap()
+            .is_match(text)
+    };
+    HIGH_RISK_WORDS
+        .iter()
+        .find(|word| contains(word))
+        .map(|word| (RiskLevel::High, *word))
+        .or_else(|| {
+            MEDIUM_RISK_WORDS
+                .iter()
+                .find(|word| contains(word))
+                .map(|word| (RiskLevel::Medium, *word))
+        })
+}
+
+/// The text of the words close to a click at `x`, `y` (fractions of the screenshot).
+fn text_near(observation: &Observation, x: f32, y: f32) -> Result<String, String> {
+    let center = (
+        (x * observation.width as f32).round() as u32,
+        (y * observation.height as f32).round() as u32,
+    );
+    let target_filename = "screenshots/screenshot_target.png";
+    let (crop_x, crop_y, _, _) = crop_and_zoom(
+        &observation.screenshot_path,
+        target_filename,
+        center,
+        RISK_OCR_CROP_SIZE,
+        RISK_OCR_ZOOM,
+    )
+    .map_err(|e| format!("Error cropping the click target: {}", e))?;
+
+    // The click and the radius in pixels of the zoomed crop
+    let click_x = center.0.saturating_sub(crop_x) * RISK_OCR_ZOOM;
+    let click_y = center.1.saturating_sub(crop_y) * RISK_OCR_ZOOM;
+    let radius = RISK_OCR_RADIUS * RISK_OCR_ZOOM;
+
+    let words: Vec<String> = ocr_words(target_filename)?
+        .into_iter()
+        .filter(|word| {
+            let dx = word
+                .x
+                .saturating_sub(click_x)
+                .max(click_x.saturating_sub(word.x + word.width));
+            let dy = word
+                .y
+                .saturating_sub(click_y)
+                .max(click_y.saturating_sub(word.y + word.height));
+            dx * dx + dy * dy <= radius * radius
+        })
+        .map(|word| word.text)
+        .collect();
+    Ok(words.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_riskiest_word() {
+        assert_eq!(
+            risky_word("The Delete button"),
+            Some((RiskLevel::High, "delete"))
+        );
+        assert_eq!(
+            risky_word("Save the file"),
+            Some((RiskLevel::Medium, "save"))
+        );
+        assert_eq!(
+            risky_word("save, then delete"),
+            Some((RiskLevel::High, "delete"))
+        );
+    }
+
+    #[test]
+    fn only_matches_whole_words() {
+        assert_eq!(risky_word("Saved drafts"), None);
+        assert_eq!(risky_word("Open the formatted report"), None);
+        assert_eq!(risky_word(""), None);
+    }
+}
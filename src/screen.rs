@@ -1,11 +1,37 @@
-use image::{Rgba, RgbaImage};
+use crate::{
+    constants::{
+        AUTO_GRID_MODEL_INTERVAL, LABEL_MIN_FONT_SIZE, LABEL_MODEL_FONT_SIZE, MARK_CANNY_HIGH,
+        MARK_CANNY_LOW, MARK_DILATE_RADIUS, MARK_MAX_COUNT, MARK_MIN_SIZE, MODEL_IMAGE_MAX_SIDE,
+        MODEL_IMAGE_SHORT_SIDE, STABILITY_DOWNSCALE, STABILITY_PIXEL_TOLERANCE,
+    },
+    emergency::is_aborted,
+    types::{
+        Capture, GridOptions, GridStyle, Mark, PreviousAction, Region, ScreenChange,
+        StabilityOptions,
+    },
+};
+use enigo::{Enigo, MouseControllable};
+use image::{Luma, Rgba, RgbaImage};
 use imageproc::{
-    drawing::{draw_filled_rect_mut, draw_line_segment_mut, draw_text_mut},
+    distance_transform::Norm,
+    drawing::{
+        draw_filled_rect_mut, draw_hollow_circle_mut, draw_hollow_rect_mut, draw_line_segment_mut,
+        draw_text_mut, text_size, Blend,
+    },
+    edges::canny,
+    morphology::dilate,
     rect::Rect,
+    region_labelling::{connected_components, Connectivity},
 };
 use rusttype::{Font, Scale};
 use screenshots::Screen;
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+static FONT_DATA: &[u8] = include_bytes!("font/DejaVuSans.ttf");
 
 pub fn get_screen_size() -> Result<(u32, u32), &'static str> {
     match Screen::all() {
@@ -21,7 +47,22 @@ pub fn get_screen_size() -> Result<(u32, u32), &'static str> {
     }
 }
 
-pub fn capture_screen_with_cursor(file_path: &str) -> Result<(), String> {
+/// The first screen in screen coordinates.
+pub fn screen_region() -> Result<Region, String> {
+    let screens = Screen::all().map_err(|e| format!("Failed to get screens: {}", e))?;
+    let info = &screens.first().ok_or("No screens found")?.display_info;
+    Ok(Region {
+        x: info.x,
+        y: info.y,
+        width: info.width,
+        height: info.height,
+    })
+}
+
+/// Captures the first screen, cropped to `region` when one is given, and saves it. The cursor
+/// is left out so the screenshot can be analysed, it is drawn on the annotated image with
+/// `add_pointer_to_image`.
+pub fn capture_screen(file_path: &str, region: Option<&Region>) -> Result<Capture, String> {
     let screens = Screen::all().map_err(|e| format!("Failed to get screens: {}", e))?;
 
     let screen = screens.get(0).ok_or("No screens found")?;
@@ -29,92 +70,729 @@ pub fn capture_screen_with_cursor(file_path: &str) -> Result<(), String> {
         .capture()
         .map_err(|e| format!("Failed to capture screen: {}", e))?;
 
+    // The pointer is reported in screen coordinates, the screenshot is in physical pixels
+    let info = &screen.display_info;
+    let scale = screenshot.width() as f32 / info.width as f32;
+    let origin = match region {
+        Some(region) => (region.x, region.y),
+        None => (info.x, info.y),
+    };
+    let screenshot = crop_to_region(screenshot, info.x, info.y, scale, region)?;
+
+    let (pointer_x, pointer_y) = Enigo::new().mouse_location();
+    let x = ((pointer_x - origin.0) as f32 * scale).round() as i32;
+    let y = ((pointer_y - origin.1) as f32 * scale).round() as i32;
+
+    let pointer = if x >= 0
+        && y >= 0
+        && (x as u32) < screenshot.width()
+        && (y as u32) < screenshot.height()
+    {
+        Some((x as u32, y as u32))
+    } else {
+        None
+    };
+
     screenshot
         .save(file_path)
-        .map_err(|e| format!("Failed to save screenshot: {}", e))
+        .map_err(|e| format!("Failed to save screenshot: {}", e))?;
+
+    Ok(Capture { pointer, scale })
 }
 
-pub fn add_grid_to_image(
+/// Draws the mouse cursor and a red circle around it at `position` in pixels of the image,
+/// in place. `scale` is the `Capture::scale` of the screenshot.
+pub fn add_pointer_to_image(
+    image_path: &str,
+    position: (u32, u32),
+    scale: f32,
+) -> Result<(), Box<dyn Error>> {
+    let mut draw = image::open(image_path)?.into_rgba8();
+    draw_cursor(&mut draw, (position.0 as i32, position.1 as i32), scale);
+    draw.save(image_path)?;
+
+    Ok(())
+}
+
+/// Crops a capture of the screen at `screen_x`, `screen_y` to a region given in screen
+/// coordinates. `scale` is the number of captured pixels per screen coordinate.
+fn crop_to_region(
+    screenshot: RgbaImage,
+    screen_x: i32,
+    screen_y: i32,
+    scale: f32,
+    region: Option<&Region>,
+) -> Result<RgbaImage, String> {
+    let region = match region {
+        Some(region) => region,
+        None => return Ok(screenshot),
+    };
+
+    let left = (((region.x - screen_x) as f32 * scale).round() as i64).max(0) as u32;
+    let top = (((region.y - screen_y) as f32 * scale).round() as i64).max(0) as u32;
+    let right = ((((region.x - screen_x) as f32 + region.width as f32) * scale).round() as u32)
+        .min(screenshot.width());
+    let bottom = ((((region.y - screen_y) as f32 + region.height as f32) * scale).round() as u32)
+        .min(screenshot.height());
+    if left >= right || top >= bottom {
+        return Err(format!("The region {:?} is not on the screen", region));
+    }
+
+    Ok(image::imageops::crop_imm(&screenshot, left, top, right - left, bottom - top).to_image())
+}
+
+/// Marks where the previous action happened: a green crosshair for a click and a yellow
+/// highlight around the focused element for typing.
+pub fn add_previous_action_to_image(
     original_image_path: &str,
     new_image_path: &str,
-    grid_interval: i32,
+    previous_action: &PreviousAction,
 ) -> Result<(), Box<dyn Error>> {
+    let draw = image::open(original_image_path)?.into_rgba8();
+    let draw = draw_previous_action(draw, previous_action);
+    draw.save(new_image_path)?;
+
+    Ok(())
+}
+
+/// Draws the previous action in place on an annotated image, where the screenshot of `size`
+/// pixels starts at `offset`, e.g. after a grid with margins.
+pub fn add_previous_action_to_annotated_image(
+    image_path: &str,
+    previous_action: &PreviousAction,
+    offset: (u32, u32),
+    size: (u32, u32),
+) -> Result<(), Box<dyn Error>> {
+    let draw = image::open(image_path)?.into_rgba8();
+    let draw = draw_previous_action_at(draw, previous_action, offset, size);
+    draw.save(image_path)?;
+
+    Ok(())
+}
+
+/// Draws the previous action onto an image, see `add_previous_action_to_image`.
+pub fn draw_previous_action(draw: RgbaImage, previous_action: &PreviousAction) -> RgbaImage {
+    let size = draw.dimensions();
+    draw_previous_action_at(draw, previous_action, (0, 0), size)
+}
+
+fn draw_previous_action_at(
+    mut draw: RgbaImage,
+    previous_action: &PreviousAction,
+    offset: (u32, u32),
+    (width, height): (u32, u32),
+) -> RgbaImage {
+    let size = label_font_size(width, height) as i32;
+    let to_pixels = |x: f32, y: f32| {
+        (
+            offset.0 as i32 + (x * width as f32).round() as i32,
+            offset.1 as i32 + (y * height as f32).round() as i32,
+        )
+    };
+
+    match previous_action {
+        PreviousAction::Click { x, y } => {
+            let (x, y) = to_pixels(*x, *y);
+            let color = Rgba([0, 200, 0, 255]);
+            for offset in -1..=1 {
+                draw_line_segment_mut(
+                    &mut draw,
+                    ((x - size * 2) as f32, (y + offset) as f32),
+                    ((x + size * 2) as f32, (y + offset) as f32),
+                    color,
+                );
+                draw_line_segment_mut(
+                    &mut draw,
+                    ((x + offset) as f32, (y - size * 2) as f32),
+                    ((x + offset) as f32, (y + size * 2) as f32),
+                    color,
+                );
+            }
+            draw_hollow_circle_mut(&mut draw, (x, y), size, color);
+        }
+        PreviousAction::Type {
+            focus: Some((x, y)),
+            ..
+        } => {
+            let (x, y) = to_pixels(*x, *y);
+            let mut canvas = Blend(draw);
+            draw_filled_rect_mut(
+                &mut canvas,
+                Rect::at(x - size * 6, y - size).of_size(size as u32 * 12, size as u32 * 2),
+                Rgba([255, 220, 0, 90]),
+            );
+            draw = canvas.0;
+            draw_hollow_rect_mut(
+                &mut draw,
+                Rect::at(x - size * 6, y - size).of_size(size as u32 * 12, size as u32 * 2),
+                Rgba([230, 180, 0, 255]),
+            );
+        }
+        PreviousAction::Type { focus: None, .. } => {}
+    }
+
+    draw
+}
+
+/// Writes `text` in a label at the bottom left corner of the image.
+pub fn draw_caption(draw: &mut RgbaImage, text: &str) -> Result<(), String> {
+    let (width, height) = draw.dimensions();
+    let font_size = label_font_size(width, height);
+    let (_, label_height) = label_size(text, font_size)?;
+    draw_label_with_background(
+        (0, height as i32 - label_height as i32),
+        text,
+        draw,
+        font_size,
+    )
+}
+
+/// Captures the first screen, cropped to `region`, without saving it or drawing the cursor.
+pub fn capture_frame(region: Option<&Region>) -> Result<RgbaImage, String> {
+    let screens = Screen::all().map_err(|e| format!("Failed to get screens: {}", e))?;
+    let screen = screens.first().ok_or("No screens found")?;
+    let frame = screen
+        .capture()
+        .map_err(|e| format!("Failed to capture screen: {}", e))?;
+
+    let info = &screen.display_info;
+    let scale = frame.width() as f32 / info.width as f32;
+    crop_to_region(frame, info.x, info.y, scale, region)
+}
+
+/// Fraction of pixels that differ between two frames. Frames of different sizes are
+/// completely different.
+pub fn frame_difference(a: &RgbaImage, b: &RgbaImage) -> f32 {
+    if a.dimensions() != b.dimensions() {
+        return 1.0;
+    }
+
+    let (width, height) = a.dimensions();
+    let mut changed = 0u64;
+    let mut total = 0u64;
+    for y in (0..height).step_by(STABILITY_DOWNSCALE as usize) {
+        for x in (0..width).step_by(STABILITY_DOWNSCALE as usize) {
+            let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+            if pa.0[..3]
+                .iter()
+                .zip(pb.0[..3].iter())
+                .any(|(ca, cb)| ca.abs_diff(*cb) > STABILITY_PIXEL_TOLERANCE)
+            {
+                changed += 1;
+            }
+            total += 1;
+        }
+    }
+
+    changed as f32 / total.max(1) as f32
+}
+
+/// Captures frames until `options.frames` consecutive ones are the same or the timeout
+/// expires. `baseline` is the frame from before the action, used to tell whether the action
+/// changed anything.
+pub async fn wait_for_stable_screen(
+    baseline: Option<&RgbaImage>,
+    options: &StabilityOptions,
+    region: Option<&Region>,
+) -> Result<ScreenChange, String> {
+    let start = Instant::now();
+    let mut previous = capture_frame(region)?;
+    let mut similar_frames = 0;
+    let mut stable = false;
+
+    while start.elapsed() < Duration::from_millis(options.timeout_ms) && !is_aborted() {
+        tokio::time::sleep(Duration::from_millis(options.poll_interval_ms)).await;
+        let frame = capture_frame(region)?;
+        if frame_difference(&previous, &frame) <= options.threshold {
+            similar_frames += 1;
+            if similar_frames >= options.frames {
+                stable = true;
+                previous = frame;
+                break;
+            }
+        } else {
+            similar_frames = 0;
+        }
+        previous = frame;
+    }
+
+    Ok(ScreenChange {
+        changed: baseline
+            .map(|baseline| frame_difference(baseline, &previous) > options.threshold)
+            .unwrap_or(true),
+        stable,
+        waited_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+fn draw_cursor(screenshot: &mut RgbaImage, position: (i32, i32), scale: f32) {
+    if let Some((cursor, (hot_x, hot_y))) = cursor_image() {
+        let cursor = image::imageops::resize(
+            &cursor,
+            (cursor.width() as f32 * scale).round().max(1.0) as u32,
+            (cursor.height() as f32 * scale).round().max(1.0) as u32,
+            image::imageops::FilterType::Triangle,
+        );
+        image::imageops::overlay(
+            screenshot,
+            &cursor,
+            (position.0 as f32 - hot_x as f32 * scale) as i64,
+            (position.1 as f32 - hot_y as f32 * scale) as i64,
+        );
+    }
+
+    // Screen captures usually leave the cursor out, so mark the pointer even without its shape
+    let (width, height) = screenshot.dimensions();
+    let radius = label_font_size(width, height) as i32;
+    for offset in 0..(radius / 5).max(2) {
+        draw_hollow_circle_mut(
+            screenshot,
+            position,
+            radius + offset,
+            Rgba([255, 0, 0, 255]),
+        );
+    }
+}
+
+/// Current cursor shape and its hotspot, read with the XFixes extension.
+#[cfg(target_os = "linux")]
+fn cursor_image() -> Option<(RgbaImage, (u32, u32))> {
+    use x11rb::protocol::xfixes::ConnectionExt as _;
+
+    let (conn, _) = x11rb::connect(None).ok()?;
+    conn.xfixes_query_version(4, 0).ok()?.reply().ok()?;
+    let reply = conn.xfixes_get_cursor_image().ok()?.reply().ok()?;
+
+    let mut cursor = RgbaImage::new(reply.width as u32, reply.height as u32);
+    for (pixel, argb) in cursor.pixels_mut().zip(reply.cursor_image.iter()) {
+        // XFixes returns premultiplied ARGB
+        let alpha = (argb >> 24) as u8;
+        let channel = |shift: u32| match alpha {
+            0 => 0,
+            _ => (((argb >> shift) & 0xff) * 255 / alpha as u32).min(255) as u8,
+        };
+        *pixel = Rgba([channel(16), channel(8), channel(0), alpha]);
+    }
+
+    Some((cursor, (reply.xhot as u32, reply.yhot as u32)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cursor_image() -> Option<(RgbaImage, (u32, u32))> {
+    None
+}
+
+/// Draws the grid and returns where the top left corner of the original image is on the new
+/// one, which has margins for some styles.
+pub fn add_grid_to_image(
+    original_image_path: &str,
+    new_image_path: &str,
+    grid: &GridOptions,
+) -> Result<(u32, u32), Box<dyn Error>> {
     let image = image::open(original_image_path)?;
 
     let image = image.into_rgba8();
     let (width, height) = image.dimensions();
+    let (draw, offset) = match grid.style {
+        GridStyle::Labels => (draw_label_grid(image, grid)?, 0),
+        GridStyle::Rulers => (draw_ruler_grid(image, grid)?, ruler_margin(width, height)?),
+        GridStyle::Cells => (draw_cell_grid(image, grid)?, 0),
+    };
+
+    // Save the image
+    draw.save(new_image_path)?;
+
+    Ok((offset, offset))
+}
+
+fn draw_label_grid(image: RgbaImage, grid: &GridOptions) -> Result<RgbaImage, String> {
+    let grid_interval = grid.interval;
+    let (width, height) = image.dimensions();
     let mut draw = image;
 
-    let font_size = (grid_interval / 10) as u32;
-    let bg_width = (font_size as f32 * 6.5) as u32;
-    let bg_height = (font_size as f32 * 1.2) as u32;
+    draw_grid_lines(&mut draw, grid, (0, 0), (width, height));
+
+    // Shrink the labels when the widest one would overlap its neighbours
+    let mut font_size = label_font_size(width, height);
+    let (widest, _) = label_size("X=100%,Y=100%", font_size)?;
+    let max_width = grid_interval as u32 * 9 / 10;
+    if widest > max_width {
+        font_size = (font_size * max_width / widest).max(LABEL_MIN_FONT_SIZE);
+    }
 
-    // Draw vertical lines and labels
+    // Draw labels at the intersections
     for x in (grid_interval as u32..width).step_by(grid_interval as usize) {
         for y in (grid_interval as u32..height).step_by(grid_interval as usize) {
-            draw_line_segment_mut(
-                &mut draw,
-                (x as f32, 0.0),
-                (x as f32, height as f32),
-                Rgba([0, 0, 255, 255]),
-            );
-
             let x_percent = ((x as f32 / width as f32) * 100.0).round() as u32;
             let y_percent = ((y as f32 / height as f32) * 100.0).round() as u32;
+            let label = format!("X={}%,Y={}%", x_percent, y_percent);
+            let (bg_width, bg_height) = label_size(&label, font_size)?;
             draw_label_with_background(
-                (x - bg_width / 2, y - bg_height / 2),
-                &format!("X={}%,Y={}%", x_percent, y_percent),
+                (
+                    x as i32 - bg_width as i32 / 2,
+                    y as i32 - bg_height as i32 / 2,
+                ),
+                &label,
                 &mut draw,
-                bg_width,
-                bg_height,
                 font_size,
             )?;
         }
     }
 
+    Ok(draw)
+}
+
+/// Places the screenshot on a larger canvas and writes the percentages in the top and left
+/// margins, so no label covers the screen content.
+fn draw_ruler_grid(image: RgbaImage, grid: &GridOptions) -> Result<RgbaImage, String> {
+    let grid_interval = grid.interval as u32;
+    let (width, height) = image.dimensions();
+
+    let font_size = label_font_size(width, height);
+    let margin = ruler_margin(width, height)?;
+
+    let mut draw =
+        RgbaImage::from_pixel(width + margin, height + margin, Rgba([255, 255, 255, 255]));
+    image::imageops::replace(&mut draw, &image, margin as i64, margin as i64);
+
+    draw_grid_lines(&mut draw, grid, (margin, margin), (width, height));
+
+    for x in (0..width).step_by(grid_interval as usize) {
+        let x_percent = ((x as f32 / width as f32) * 100.0).round() as u32;
+        draw_filled_rect_mut(
+            &mut draw,
+            Rect::at((margin + x) as i32, (margin - font_size / 2) as i32)
+                .of_size(grid.thickness.max(1), font_size / 2),
+            Rgba([0, 0, 0, 255]),
+        );
+        draw_label_with_background(
+            ((margin + x + 2) as i32, 0),
+            &format!("{}%", x_percent),
+            &mut draw,
+            font_size,
+        )?;
+    }
+
+    for y in (0..height).step_by(grid_interval as usize) {
+        let y_percent = ((y as f32 / height as f32) * 100.0).round() as u32;
+        draw_filled_rect_mut(
+            &mut draw,
+            Rect::at((margin - font_size / 2) as i32, (margin + y) as i32)
+                .of_size(font_size / 2, grid.thickness.max(1)),
+            Rgba([0, 0, 0, 255]),
+        );
+        draw_label_with_background(
+            (0, (margin + y + 2) as i32),
+            &format!("{}%", y_percent),
+            &mut draw,
+            font_size,
+        )?;
+    }
+
+    Ok(draw)
+}
+
+/// Width of the margins `draw_ruler_grid` adds above and left of the screenshot.
+fn ruler_margin(width: u32, height: u32) -> Result<u32, String> {
+    let font_size = label_font_size(width, height);
+    let (bg_width, _) = label_size("100%", font_size)?;
+    Ok(bg_width + font_size / 2)
+}
+
+/// Divides the screenshot into cells labelled like a spreadsheet (A1, B1, ..., A2, ...).
+fn draw_cell_grid(image: RgbaImage, grid: &GridOptions) -> Result<RgbaImage, String> {
+    let grid_interval = grid.interval as u32;
+    let (width, height) = image.dimensions();
+    let mut draw = image;
+
+    draw_grid_lines(&mut draw, grid, (0, 0), (width, height));
+
+    let font_size = label_font_size(width, height)
+        .min(grid_interval / 3)
+        .max(LABEL_MIN_FONT_SIZE);
+
+    for (row, y) in (0..height).step_by(grid_interval as usize).enumerate() {
+        for (column, x) in (0..width).step_by(grid_interval as usize).enumerate() {
+            draw_label_with_background(
+                ((x + grid.thickness) as i32, (y + grid.thickness) as i32),
+                &cell_label(column as u32, row as u32),
+                &mut draw,
+                font_size,
+            )?;
+        }
+    }
+
+    Ok(draw)
+}
+
+/// Draws the grid lines of an area of `size` pixels starting at `offset`, blending the
+/// line color over the image.
+fn draw_grid_lines(draw: &mut RgbaImage, grid: &GridOptions, offset: (u32, u32), size: (u32, u32)) {
+    let grid_interval = grid.interval as u32;
+    let thickness = grid.thickness.max(1);
+    let color = Rgba(grid.color);
+
+    let mut canvas = Blend(std::mem::take(draw));
+
+    // Draw vertical lines
+    for x in (grid_interval..size.0).step_by(grid_interval as usize) {
+        let left = (offset.0 + x).saturating_sub(thickness / 2);
+        draw_filled_rect_mut(
+            &mut canvas,
+            Rect::at(left as i32, offset.1 as i32).of_size(thickness, size.1),
+            color,
+        );
+    }
+
     // Draw horizontal lines
-    for y in (grid_interval as u32..height).step_by(grid_interval as usize) {
-        draw_line_segment_mut(
+    for y in (grid_interval..size.1).step_by(grid_interval as usize) {
+        let top = (offset.1 + y).saturating_sub(thickness / 2);
+        draw_filled_rect_mut(
+            &mut canvas,
+            Rect::at(offset.0 as i32, top as i32).of_size(size.0, thickness),
+            color,
+        );
+    }
+
+    *draw = canvas.0;
+}
+
+/// Spreadsheet style label of a grid cell, both indexes start at 0: (0, 0) is "A1",
+/// (27, 4) is "AB5".
+pub fn cell_label(column: u32, row: u32) -> String {
+    let mut letters = Vec::new();
+    let mut column = column + 1;
+    while column > 0 {
+        column -= 1;
+        letters.push((b'A' + (column % 26) as u8) as char);
+        column /= 26;
+    }
+    letters.iter().rev().collect::<String>() + &(row + 1).to_string()
+}
+
+/// Center in pixels of the cell with the given label, or `None` if the label is malformed
+/// or outside of the image.
+pub fn cell_center(label: &str, grid_interval: u32, width: u32, height: u32) -> Option<(u32, u32)> {
+    let label = label.trim().to_uppercase();
+    let split = label.find(|c: char| c.is_ascii_digit())?;
+    let (letters, digits) = label.split_at(split);
+    if letters.is_empty() || letters.len() > 3 || !letters.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+
+    let column = letters
+        .bytes()
+        .fold(0u32, |acc, b| acc * 26 + (b - b'A') as u32 + 1)
+        - 1;
+    let row = digits.parse::<u32>().ok()?.checked_sub(1)?;
+
+    let left = column.checked_mul(grid_interval)?;
+    let top = row.checked_mul(grid_interval)?;
+    if left >= width || top >= height {
+        return None;
+    }
+
+    Some((
+        left + grid_interval.min(width - left) / 2,
+        top + grid_interval.min(height - top) / 2,
+    ))
+}
+
+pub fn add_marks_to_image(
+    original_image_path: &str,
+    new_image_path: &str,
+) -> Result<Vec<Mark>, Box<dyn Error>> {
+    let image = image::open(original_image_path)?;
+    let mut draw = image.into_rgba8();
+
+    let marks = detect_ui_elements(&draw);
+
+    let (width, height) = draw.dimensions();
+    let font_size = label_font_size(width, height);
+    for mark in &marks {
+        draw_hollow_rect_mut(
             &mut draw,
-            (0.0, y as f32),
-            (width as f32, y as f32),
-            Rgba([0, 0, 255, 255]),
+            Rect::at(mark.x as i32, mark.y as i32).of_size(mark.width, mark.height),
+            Rgba([255, 0, 0, 255]),
         );
+
+        let label = mark.id.to_string();
+        let (_, bg_height) = label_size(&label, font_size)?;
+        draw_label_with_background(
+            (mark.x as i32, mark.y as i32 - bg_height as i32),
+            &label,
+            &mut draw,
+            font_size,
+        )?;
     }
 
-    // Save the image
     draw.save(new_image_path)?;
 
-    Ok(())
+    Ok(marks)
 }
 
+/// Finds candidate UI elements by grouping nearby edges into connected components.
+/// Marks are numbered in reading order, starting at 1.
+pub fn detect_ui_elements(image: &RgbaImage) -> Vec<Mark> {
+    let (width, height) = image.dimensions();
+
+    let gray = image::imageops::grayscale(image);
+    let edges = canny(&gray, MARK_CANNY_LOW, MARK_CANNY_HIGH);
+    // Join the edges of a single element (text glyphs, icon strokes) into one blob
+    let joined = dilate(&edges, Norm::LInf, MARK_DILATE_RADIUS);
+    let labels = connected_components(&joined, Connectivity::Eight, Luma([0u8]));
+
+    let mut bounds: HashMap<u32, (u32, u32, u32, u32)> = HashMap::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        if label[0] == 0 {
+            continue;
+        }
+        let bound = bounds.entry(label[0]).or_insert((x, y, x, y));
+        bound.0 = bound.0.min(x);
+        bound.1 = bound.1.min(y);
+        bound.2 = bound.2.max(x);
+        bound.3 = bound.3.max(y);
+    }
+
+    // Drop specks and anything as large as a window or panel
+    let mut boxes: Vec<(u32, u32, u32, u32)> = bounds
+        .into_values()
+        .map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
+        .filter(|&(_, _, w, h)| {
+            w >= MARK_MIN_SIZE && h >= MARK_MIN_SIZE && w <= width / 2 && h <= height / 2
+        })
+        .collect();
+
+    // Keep the largest elements when there are too many, then number them in reading order
+    boxes.sort_by_key(|&(_, _, w, h)| std::cmp::Reverse(w * h));
+    boxes.truncate(MARK_MAX_COUNT);
+    boxes.sort_by_key(|&(x, y, _, _)| (y / MARK_MIN_SIZE, x));
+
+    boxes
+        .into_iter()
+        .enumerate()
+        .map(|(i, (x, y, width, height))| Mark {
+            id: i as u32 + 1,
+            x,
+            y,
+            width,
+            height,
+        })
+        .collect()
+}
+
+/// Crops a square of `crop_size` pixels around `center` (shifted to stay inside the image)
+/// and scales it up by `zoom_factor`. Returns the crop rectangle as (x, y, width, height)
+/// in the original image's pixels.
+pub fn crop_and_zoom(
+    original_image_path: &str,
+    new_image_path: &str,
+    center: (u32, u32),
+    crop_size: u32,
+    zoom_factor: u32,
+) -> Result<(u32, u32, u32, u32), Box<dyn Error>> {
+    let image = image::open(original_image_path)?;
+    let (width, height) = (image.width(), image.height());
+
+    let crop_width = crop_size.min(width);
+    let crop_height = crop_size.min(height);
+    let x = center
+        .0
+        .saturating_sub(crop_width / 2)
+        .min(width - crop_width);
+    let y = center
+        .1
+        .saturating_sub(crop_height / 2)
+        .min(height - crop_height);
+
+    let zoomed = image.crop_imm(x, y, crop_width, crop_height).resize_exact(
+        crop_width * zoom_factor,
+        crop_height * zoom_factor,
+        image::imageops::FilterType::Lanczos3,
+    );
+    zoomed.save(new_image_path)?;
+
+    Ok((x, y, crop_width, crop_height))
+}
+
+/// Scale between the screenshot and the image the model actually looks at. Vision models
+/// downscale large images, so a label must be `scale` times bigger to stay readable.
+pub fn model_image_scale(width: u32, height: u32) -> f32 {
+    let fit = (MODEL_IMAGE_MAX_SIDE as f32 / width.max(height) as f32).min(1.0);
+    let short_side = width.min(height) as f32 * fit;
+    let shrink = (MODEL_IMAGE_SHORT_SIDE as f32 / short_side).min(1.0);
+    1.0 / (fit * shrink)
+}
+
+/// Font size of the labels drawn on a screenshot of the given size.
+pub fn label_font_size(width: u32, height: u32) -> u32 {
+    ((LABEL_MODEL_FONT_SIZE as f32 * model_image_scale(width, height)).round() as u32)
+        .max(LABEL_MIN_FONT_SIZE)
+}
+
+/// Grid interval that gives cells of roughly the same size to the model on any screen. It is
+/// chosen in pixels of the downscaled image the model looks at, wide enough for the labels of
+/// `style` to keep their size, and scaled back to the screenshot.
+pub fn auto_grid_interval(width: u32, height: u32, style: GridStyle) -> Result<i32, String> {
+    // Rulers add margins, so the image sent to the model is larger than the screenshot
+    let margin = match style {
+        GridStyle::Rulers => ruler_margin(width, height)?,
+        GridStyle::Labels | GridStyle::Cells => 0,
+    };
+    let scale = model_image_scale(width + margin, height + margin);
+
+    let model_interval = match style {
+        // `draw_label_grid` shrinks labels wider than 9/10 of the interval
+        GridStyle::Labels => {
+            let (widest, _) = label_size("X=100%,Y=100%", LABEL_MODEL_FONT_SIZE)?;
+            (AUTO_GRID_MODEL_INTERVAL as f32).max(widest as f32 * 10.0 / 9.0)
+        }
+        GridStyle::Rulers | GridStyle::Cells => AUTO_GRID_MODEL_INTERVAL as f32,
+    };
+
+    // Round up to a multiple of 10 so the percentages stay readable
+    Ok(((model_interval * scale / 10.0).ceil() as i32 * 10).max(10))
+}
+
+fn load_font() -> Result<Font<'static>, String> {
+    Font::try_from_bytes(FONT_DATA).ok_or_else(|| "Failed to load font".to_string())
+}
+
+/// Size of the background drawn behind a label.
+fn label_size(text: &str, font_size: u32) -> Result<(u32, u32), String> {
+    let font = load_font()?;
+    let scale = Scale::uniform(font_size as f32);
+    let (text_width, _) = text_size(scale, &font, text);
+    Ok((
+        text_width.max(0) as u32 + font_size / 3,
+        (font_size as f32 * 1.2) as u32,
+    ))
+}
+
+/// Draws `text` on a white background with its top left corner at `position`. The label is
+/// moved inside the image when the position would put part of it outside.
 fn draw_label_with_background(
-    position: (u32, u32),
+    position: (i32, i32),
     text: &str,
     draw: &mut RgbaImage,
-    bg_width: u32,
-    bg_height: u32,
     font_size: u32,
 ) -> Result<(), String> {
-    let scale = Scale {
-        x: font_size as f32,
-        y: font_size as f32,
-    };
+    let scale = Scale::uniform(font_size as f32);
+    let font = load_font()?;
 
-    let font_data = include_bytes!("font/DejaVuSans.ttf") as &[u8];
-    let font =
-        Font::try_from_vec(font_data.to_vec()).ok_or_else(|| "Failed to load font".to_string())?;
+    let (bg_width, bg_height) = label_size(text, font_size)?;
+    let (width, height) = draw.dimensions();
+    let x_position = position.0.min(width as i32 - bg_width as i32).max(0);
+    let y_position = position.1.min(height as i32 - bg_height as i32).max(0);
 
-    let rect = Rect::at(position.0 as i32, position.1 as i32).of_size(bg_width, bg_height);
+    let rect = Rect::at(x_position, y_position).of_size(bg_width, bg_height);
     draw_filled_rect_mut(draw, rect, Rgba([255, 255, 255, 255]));
 
-    let x_position = position.0;
-    let y_position = position.1 + bg_height / 8;
     draw_text_mut(
         draw,
         Rgba([0, 0, 0, 255]),
-        x_position as i32,
-        y_position as i32,
+        x_position + (font_size / 6) as i32,
+        y_position + (bg_height / 8) as i32,
         scale,
         &font,
         text,
@@ -122,3 +800,70 @@ fn draw_label_with_background(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_center_of_a_cell() {
+        assert_eq!(cell_center("A1", 100, 1000, 500), Some((50, 50)));
+        assert_eq!(cell_center(" c4 ", 100, 1000, 500), Some((250, 350)));
+        assert_eq!(cell_center("AA1", 10, 1000, 500), Some((265, 5)));
+    }
+
+    #[test]
+    fn shrinks_cells_cut_by_the_edge() {
+        assert_eq!(cell_center("J5", 100, 950, 450), Some((925, 425)));
+    }
+
+    #[test]
+    fn rejects_cells_outside_of_the_image() {
+        assert_eq!(cell_center("K1", 100, 1000, 500), None);
+        assert_eq!(cell_center("A6", 100, 1000, 500), None);
+        assert_eq!(cell_center("A0", 100, 1000, 500), None);
+    }
+
+    #[test]
+    fn rejects_labels_that_overflow() {
+        assert_eq!(cell_center("A99999999", 100, 1000, 500), None);
+        assert_eq!(cell_center("ZZZ99999999999", 100, 1000, 500), None);
+        assert_eq!(cell_center("ZZZ1", u32::MAX / 2, u32::MAX, 500), None);
+    }
+
+    #[test]
+    fn rejects_malformed_labels() {
+        assert_eq!(cell_center("", 100, 1000, 500), None);
+        assert_eq!(cell_center("12", 100, 1000, 500), None);
+        assert_eq!(cell_center("AAAA1", 100, 1000, 500), None);
+        assert_eq!(cell_center("A1B", 100, 1000, 500), None);
+    }
+
+    #[test]
+    fn auto_grid_keeps_cells_the_same_size_for_the_model() {
+        for (width, height) in [(1280, 800), (1920, 1080), (3840, 2160), (5120, 2880)] {
+            let interval = auto_grid_interval(width, height, GridStyle::Cells).unwrap();
+            let model_interval = interval as f32 / model_image_scale(width, height);
+            assert!(model_interval >= AUTO_GRID_MODEL_INTERVAL as f32);
+            assert!(model_interval < AUTO_GRID_MODEL_INTERVAL as f32 + 10.0);
+        }
+    }
+
+    #[test]
+    fn auto_grid_labels_fit_between_the_lines() {
+        for (width, height) in [(1280, 800), (1920, 1080), (3840, 2160), (5120, 2880)] {
+            let interval = auto_grid_interval(width, height, GridStyle::Labels).unwrap();
+            let font_size = label_font_size(width, height);
+            let (widest, _) = label_size("X=100%,Y=100%", font_size).unwrap();
+            assert!(widest <= interval as u32 * 9 / 10, "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn labels_round_trip() {
+        for (column, row) in [(0, 0), (25, 3), (26, 9), (701, 0)] {
+            let (x, y) = cell_center(&cell_label(column, row), 10, 10_000, 100).unwrap();
+            assert_eq!((x / 10, y / 10), (column, row));
+        }
+    }
+}
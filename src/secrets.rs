@@ -0,0 +1,260 @@
+use crate::constants::{
+    SECRET_ENV_PREFIX, VAULT_PASSPHRASE_ENV, VAULT_PBKDF2_ITERATIONS, VAULT_VERSION,
+};
+use base64::{engine::general_purpose, Engine as _};
+use regex::{Captures, Regex};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, fmt, fs, num::NonZeroU32, path::Path};
+
+/// Values the model can type by placeholder, e.g. `{{secret:staging_pw}}`, without ever
+/// seeing them. Names are lowercase.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    values: BTreeMap<String, String>,
+}
+
+// Only the names, so secrets never end up in debug output
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secrets")
+            .field("names", &self.names())
+            .finish()
+    }
+}
+
+impl Secrets {
+    /// Secrets from the vault, if one is given, and from `AUTO_PILOT_SECRET_<NAME>`
+    /// environment variables, which take precedence.
+    pub fn load(vault: Option<&Path>) -> Result<Self, String> {
+        let mut values = match vault {
+            Some(vault) => read_vault(vault, &vault_passphrase()?)?,
+            None => BTreeMap::new(),
+        };
+        for (key, value) in env::vars() {
+            if let Some(name) = key.strip_prefix(SECRET_ENV_PREFIX) {
+                values.insert(name.to_lowercase(), value);
+            }
+        }
+        Ok(Secrets { values })
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.values.keys().map(String::as_str).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Replaces every placeholder in `text` with its secret. Fails on unknown secrets.
+    pub fn substitute(&self, text: &str) -> Result<String, String> {
+        let placeholder = placeholder_pattern();
+        if let Some(unknown) = placeholder
+            .captures_iter(text)
+            .map(|captures| captures[1].to_lowercase())
+            .find(|name| !self.values.contains_key(name))
+        {
+            return Err(format!("Unknown secret \"{}\"", unknown));
+        }
+        Ok(placeholder
+            .replace_all(text, |captures: &Captures| {
+                self.values[&captures[1].to_lowercase()].clone()
+            })
+            .into_owned())
+    }
+}
+
+pub fn placeholder(name: &str) -> String {
+    format!("{{{{secret:{}}}}}", name)
+}
+
+fn placeholder_pattern() -> Regex {
+    Regex::new(r"\{\{secret:([A-Za-z0-9_.-]+)\}\}").unwrap()
+}
+
+/// The encrypted vault file. The secrets are a JSON object of names to values, encrypted with
+/// ChaCha20-Poly1305 under a key derived from the passphrase with PBKDF2.
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn vault_passphrase() -> Result<String, String> {
+    env::var(VAULT_PASSPHRASE_ENV)
+        .map_err(|_| format!("Set {} to open the secrets vault", VAULT_PASSPHRASE_ENV))
+}
+
+fn vault_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey, String> {
+    let iterations =
+        NonZeroU32::new(iterations).ok_or("Invalid number of iterations in the vault")?;
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| "Failed to create the vault key".to_string())?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Reads all secrets of a vault. A missing vault is empty.
+fn read_vault(path: &Path, passphrase: &str) -> Result<BTreeMap<String, String>, String> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read vault {}: {}", path.display(), e))?;
+    let vault: VaultFile = serde_json::from_str(&content)
+        .map_err(|e| format!("Invalid vault {}: {}", path.display(), e))?;
+    if vault.version != VAULT_VERSION {
+        return Err(format!(
+            "Unsupported vault version {}, expected {}",
+            vault.version, VAULT_VERSION
+        ));
+    }
+
+    let decode = |field: &str| {
+        general_purpose::STANDARD
+            .decode(field)
+            .map_err(|e| format!("Invalid vault {}: {}", path.display(), e))
+    };
+    let salt = decode(&vault.salt)?;
+    let nonce = Nonce::try_assume_unique_for_key(&decode(&vault.nonce)?)
+        .map_err(|_| format!("Invalid nonce in vault {}", path.display()))?;
+    let mut data = decode(&vault.ciphertext)?;
+
+    let plaintext = vault_key(passphrase, &salt, vault.iterations)?
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| {
+            format!(
+                "Failed to open vault {}: wrong passphrase or damaged file",
+                path.display()
+            )
+        })?;
+    serde_json::from_slice(plaintext)
+        .map_err(|e| format!("Invalid secrets in vault {}: {}", path.display(), e))
+}
+
+/// Encrypts `secrets` into the vault with a new salt and nonce, replacing its content.
+fn write_vault(
+    path: &Path,
+    passphrase: &str,
+    secrets: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    let random = SystemRandom::new();
+    let mut salt = [0; 16];
+    let mut nonce = [0; NONCE_LEN];
+    random
+        .fill(&mut salt)
+        .and_then(|_| random.fill(&mut nonce))
+        .map_err(|_| "Failed to generate random bytes".to_string())?;
+
+    let mut data = serde_json::to_vec(secrets)
+        .map_err(|e| format!("Failed to serialize the secrets: {}", e))?;
+    vault_key(passphrase, &salt, VAULT_PBKDF2_ITERATIONS)?
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| "Failed to encrypt the secrets".to_string())?;
+
+    let vault = VaultFile {
+        version: VAULT_VERSION,
+        iterations: VAULT_PBKDF2_ITERATIONS,
+        salt: general_purpose::STANDARD.encode(salt),
+        nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: general_purpose::STANDARD.encode(data),
+    };
+    let content = serde_json::to_string_pretty(&vault)
+        .map_err(|e| format!("Failed to serialize the vault: {}", e))?;
+    fs::write(path, content).map_err(|e| format!("Failed to write vault {}: {}", path.display(), e))
+}
+
+/// Adds or replaces a secret in the vault, creating the vault if needed.
+pub fn set_secret(path: &Path, name: &str, value: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-');
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format!(
+            "Invalid secret name \"{}\", use letters, digits, _, . and -",
+            name
+        ));
+    }
+    let passphrase = vault_passphrase()?;
+    let mut secrets = read_vault(path, &passphrase)?;
+    secrets.insert(name.to_lowercase(), value.to_string());
+    write_vault(path, &passphrase, &secrets)
+}
+
+pub fn remove_secret(path: &Path, name: &str) -> Result<(), String> {
+    let passphrase = vault_passphrase()?;
+    let mut secrets = read_vault(path, &passphrase)?;
+    if secrets.remove(&name.to_lowercase()).is_none() {
+        return Err(format!("No secret \"{}\" in {}", name, path.display()));
+    }
+    write_vault(path, &passphrase, &secrets)
+}
+
+pub fn list_secrets(path: &Path) -> Result<Vec<String>, String> {
+    Ok(read_vault(path, &vault_passphrase()?)?
+        .into_keys()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets() -> Secrets {
+        Secrets {
+            values: BTreeMap::from([
+                ("staging_pw".to_string(), "hunter2".to_string()),
+                ("api.key-2".to_string(), "abc".to_string()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        assert_eq!(
+            secrets()
+                .substitute("user {{secret:staging_pw}} {{secret:API.Key-2}}")
+                .unwrap(),
+            "user hunter2 abc"
+        );
+        assert_eq!(secrets().substitute("no secrets").unwrap(), "no secrets");
+        assert_eq!(
+            secrets()
+                .substitute("{{secret:}} {secret:staging_pw}")
+                .unwrap(),
+            "{{secret:}} {secret:staging_pw}"
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_secrets() {
+        assert_eq!(
+            secrets()
+                .substitute("{{secret:staging_pw}} {{secret:Prod_PW}}")
+                .unwrap_err(),
+            "Unknown secret \"prod_pw\""
+        );
+    }
+
+    #[test]
+    fn round_trips_the_vault() {
+        let path = env::temp_dir().join(format!("auto-pilot-vault-{}.json", std::process::id()));
+        write_vault(&path, "passphrase", &secrets().values).unwrap();
+        assert_eq!(read_vault(&path, "passphrase").unwrap(), secrets().values);
+        assert!(read_vault(&path, "wrong").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
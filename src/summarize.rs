@@ -1,11 +1,13 @@
 use crate::{
     action::send_message_to_openai,
     constants::MAX_TOKENS,
-    parsers::format_summary_prompt,
-    screen::capture_screen_with_cursor,
+    parsers::{format_partial_summary_prompt, format_summary_prompt},
+    screen::capture_screen,
     types::{
-        ImageMessage, ImageMessageContent, ImageUrl, Message, OpenAIRequest, Role, TextMessage,
+        Config, ImageMessage, ImageMessageContent, ImageUrl, Message, OpenAIRequest, Role,
+        TextMessage,
     },
+    window::resolve_region,
 };
 use base64::{engine::general_purpose, Engine as _};
 use std::{error::Error, fs, path::Path};
@@ -13,6 +15,24 @@ use std::{error::Error, fs, path::Path};
 pub async fn summarize(
     messages: &mut Vec<Message>,
     objective: &str,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    summarize_with_prompt(messages, format_summary_prompt(objective), config).await
+}
+
+/// Summary of the progress made before the run was stopped.
+pub async fn summarize_partial(
+    messages: &mut Vec<Message>,
+    objective: &str,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    summarize_with_prompt(messages, format_partial_summary_prompt(objective), config).await
+}
+
+async fn summarize_with_prompt(
+    messages: &mut Vec<Message>,
+    summary_prompt: String,
+    config: &Config,
 ) -> Result<String, Box<dyn Error>> {
     let screenshots_dir = "screenshots";
     if !Path::new(screenshots_dir).exists() {
@@ -21,13 +41,16 @@ pub async fn summarize(
 
     let screenshot_filename = format!("{}/summary_screenshot.png", screenshots_dir);
 
-    capture_screen_with_cursor(&screenshot_filename)?;
+    // Limited to the same part of the screen as the steps
+    let region = resolve_region(config.region.as_ref())?;
+    capture_screen(&screenshot_filename, region.as_ref())?;
+    if let Some(redactor) = &config.redactor {
+        redactor.redact(&screenshot_filename, region.as_ref())?;
+    }
 
     let img_file = fs::read(&screenshot_filename)?;
     let img_base64 = general_purpose::STANDARD.encode(&img_file);
 
-    let summary_prompt = format_summary_prompt(objective);
-
     let vision_message = Message::ImageMessage(ImageMessage {
         role: Role::User,
         content: vec![
@@ -51,7 +74,7 @@ pub async fn summarize(
         max_tokens: MAX_TOKENS,
     };
 
-    let content = send_message_to_openai(payload)
+    let (content, _) = send_message_to_openai(payload)
         .await
         .map_err(|e| format!("Error sending message to OpenAI: {}", e))?;
 
@@ -0,0 +1,89 @@
+use crate::{
+    constants::{TAKEOVER_POINTER_TOLERANCE, TAKEOVER_POLL_INTERVAL_MS},
+    terminal::{discard_pending_input, read_line},
+    types::UserActivity,
+    window::active_window,
+};
+use colored::Colorize;
+use enigo::{Enigo, MouseControllable};
+use std::time::{Duration, Instant};
+
+/// The pointer position and focused window right now. The focused window is `None` when it
+/// can't be read, e.g. without an EWMH window manager.
+pub fn user_activity() -> UserActivity {
+    UserActivity {
+        pointer: Enigo::new().mouse_location(),
+        active_window: active_window().ok().flatten(),
+    }
+}
+
+/// What changed since `expected` was taken, if the user moved the pointer or switched windows.
+pub fn detect_takeover(expected: &UserActivity) -> Option<String> {
+    let current = user_activity();
+    let (x, y) = current.pointer;
+    let (expected_x, expected_y) = expected.pointer;
+    if (x - expected_x).abs() > TAKEOVER_POINTER_TOLERANCE
+        || (y - expected_y).abs() > TAKEOVER_POINTER_TOLERANCE
+    {
+        return Some(format!(
+            "the pointer moved from {},{} to {},{}",
+            expected_x, expected_y, x, y
+        ));
+    }
+    match (expected.active_window, current.active_window) {
+        (Some(expected_window), Some(window)) if expected_window != window => {
+            Some("the focused window changed".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Pauses the run after the user took over the computer. Without `resume_after_idle_ms`
+/// the user resumes with Enter, otherwise the run resumes once the pointer and focused window
+/// stayed still for that long.
+pub async fn wait_for_resume(
+    takeover: &str,
+    resume_after_idle_ms: Option<u64>,
+) -> Result<(), String> {
+    let resume_hint = match resume_after_idle_ms {
+        Some(idle_ms) => format!(
+            "resuming after {:.1} s without activity",
+            idle_ms as f32 / 1000.0
+        ),
+        None => "press Enter to resume".to_string(),
+    };
+    println!(
+        "{} {} The user took over, {}: {}",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Paused]".bright_magenta(),
+        takeover,
+        resume_hint
+    );
+
+    match resume_after_idle_ms {
+        Some(idle_ms) => {
+            let mut last_activity = user_activity();
+            let mut idle_since = Instant::now();
+            while idle_since.elapsed() < Duration::from_millis(idle_ms) {
+                tokio::time::sleep(Duration::from_millis(TAKEOVER_POLL_INTERVAL_MS)).await;
+                if detect_takeover(&last_activity).is_some() {
+                    last_activity = user_activity();
+                    idle_since = Instant::now();
+                }
+            }
+        }
+        None => {
+            discard_pending_input();
+            if read_line().await?.is_none() {
+                return Err("The user took over and stdin is closed, nobody can resume the run. Use --resume-after-idle to resume automatically".to_string());
+            }
+        }
+    }
+
+    println!(
+        "{} {} Resumed",
+        "[Auto-Pilot-Computer]".blue(),
+        "[Paused]".bright_magenta()
+    );
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::{io, thread};
+use tokio::sync::oneshot;
+
+/// Reads a line from stdin without blocking the async task, so Ctrl+C and an emergency stop
+/// can end the run while waiting for the user. `None` when stdin is closed.
+pub async fn read_line() -> Result<Option<String>, String> {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let read = io::stdin().read_line(&mut line).map(|read| (read, line));
+        let _ = sender.send(read);
+    });
+    let (read, line) = receiver
+        .await
+        .map_err(|e| format!("Failed to wait for the user: {}", e))?
+        .map_err(|e| format!("Failed to read the answer: {}", e))?;
+    Ok((read > 0).then_some(line))
+}
+
+/// Reads a line from stdin without showing it in the terminal, for secrets.
+pub fn read_hidden_line() -> Result<String, String> {
+    let hidden = HiddenInput::start();
+    let mut line = String::new();
+    let read = io::stdin().read_line(&mut line);
+    drop(hidden);
+    read.map_err(|e| format!("Failed to read the answer: {}", e))?;
+    Ok(line)
+}
+
+/// Turns the echo of the terminal off until it is dropped. Does nothing when stdin is not a
+/// terminal.
+#[cfg(unix)]
+struct HiddenInput {
+    original: Option<libc::termios>,
+}
+
+#[cfg(unix)]
+impl HiddenInput {
+    fn start() -> Self {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return HiddenInput { original: None };
+        }
+        let mut hidden = termios;
+        // The newline is still shown, so the next output starts on its own line
+        hidden.c_lflag &= !libc::ECHO;
+        hidden.c_lflag |= libc::ECHONL;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) };
+        HiddenInput {
+            original: Some(termios),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct HiddenInput;
+
+#[cfg(not(unix))]
+impl HiddenInput {
+    fn start() -> Self {
+        HiddenInput
+    }
+}
+
+/// Drops input typed into the terminal before a question is asked, e.g. keys the agent typed
+/// while the terminal had the focus, so they can't answer the question.
+#[cfg(unix)]
+pub fn discard_pending_input() {
+    // Fails harmlessly when stdin is not a terminal, piped answers are kept
+    unsafe {
+        libc::tcflush(libc::STDIN_FILENO, libc::TCIFLUSH);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn discard_pending_input() {}
@@ -0,0 +1,123 @@
+use crate::{
+    parsers::click_detail_position,
+    recorder::load_run,
+    screen::{draw_caption, draw_previous_action},
+    types::{GifOptions, PreviousAction, TrajectoryStep},
+};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    imageops::{self, FilterType},
+    Delay, Frame, RgbaImage,
+};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+// Captions longer than this are cut so they fit on one line
+const MAX_CAPTION_CHARS: usize = 100;
+// 1 is the best and slowest quantization, 30 the fastest
+const GIF_ENCODER_SPEED: i32 = 10;
+
+/// Stitches the screenshots of a recorded run into an animated GIF, with the action of
+/// every step drawn on its screenshot and described in a caption. Returns the path of the GIF.
+pub fn export_gif(
+    run_dir: &Path,
+    output: Option<&Path>,
+    options: &GifOptions,
+) -> Result<PathBuf, String> {
+    let (_, steps) = load_run(run_dir)?;
+    if steps.is_empty() {
+        return Err("The run has no steps".to_string());
+    }
+
+    let output = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| run_dir.join("timelapse.gif"));
+    let file = File::create(&output)
+        .map_err(|e| format!("Failed to create {}: {}", output.display(), e))?;
+    let mut encoder = GifEncoder::new_with_speed(file, GIF_ENCODER_SPEED);
+    encoder
+        .set_repeat(Repeat::Infinite)
+        .map_err(|e| format!("Failed to write the GIF: {}", e))?;
+
+    let mut size = None;
+    let mut last_click = None;
+    for step in &steps {
+        let frame = render_frame(run_dir, step, &mut last_click)?;
+        let (width, height) = *size.get_or_insert_with(|| {
+            let (width, height) = frame.dimensions();
+            (
+                ((width as f32 * options.scale).round() as u32).max(1),
+                ((height as f32 * options.scale).round() as u32).max(1),
+            )
+        });
+        // Every frame gets the size of the first one, screenshots of a window can change size
+        let frame = imageops::resize(&frame, width, height, FilterType::Triangle);
+
+        encoder
+            .encode_frame(Frame::from_parts(
+                frame,
+                0,
+                0,
+                Delay::from_numer_denom_ms(options.frame_duration_ms, 1),
+            ))
+            .map_err(|e| format!("Failed to write frame {}: {}", step.step, e))?;
+    }
+
+    Ok(output)
+}
+
+/// The raw screenshot of a step with its action drawn on it. `last_click` follows the clicks
+/// so that typing can be shown on the element it went to.
+fn render_frame(
+    run_dir: &Path,
+    step: &TrajectoryStep,
+    last_click: &mut Option<(f32, f32)>,
+) -> Result<RgbaImage, String> {
+    let screenshot = image::open(run_dir.join(&step.screenshot))
+        .map_err(|e| format!("Failed to open {}: {}", step.screenshot, e))?
+        .into_rgba8();
+
+    let action = match step.action.as_str() {
+        "CLICK" => {
+            *last_click = step.click.as_ref().and_then(click_detail_position);
+            last_click.map(|(x, y)| PreviousAction::Click { x, y })
+        }
+        "TYPE" => Some(PreviousAction::Type {
+            text: step.action_detail.clone(),
+            focus: *last_click,
+        }),
+        _ => {
+            *last_click = None;
+            None
+        }
+    };
+    let mut frame = match &action {
+        Some(action) => draw_previous_action(screenshot, action),
+        None => screenshot,
+    };
+
+    draw_caption(&mut frame, &caption(step))?;
+
+    Ok(frame)
+}
+
+fn caption(step: &TrajectoryStep) -> String {
+    let description = match step.action.as_str() {
+        "CLICK" => step
+            .click
+            .as_ref()
+            .and_then(|click| click["description"].as_str())
+            .unwrap_or_default()
+            .to_string(),
+        "DONE" => step.result.clone(),
+        _ => step.action_detail.clone(),
+    };
+    let caption = format!("{}. {} {}", step.step, step.action, description);
+
+    match caption.char_indices().nth(MAX_CAPTION_CHARS) {
+        Some((end, _)) => format!("{}...", &caption[..end]),
+        None => caption,
+    }
+}
@@ -47,3 +47,518 @@ pub enum Message {
     TextMessage(TextMessage),
     ImageMessage(ImageMessage),
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Annotation {
+    /// Percentage grid drawn over the screenshot
+    Grid,
+    /// Numbered boxes drawn around detected UI elements (set-of-marks)
+    Marks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum GridStyle {
+    /// Percentage labels at every intersection of the grid lines
+    Labels,
+    /// Percentage rulers drawn in margins outside the screenshot
+    Rulers,
+    /// Cells labelled A1, B1, ... that are clicked by their label
+    Cells,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GridOptions {
+    pub interval: i32,
+    /// Pick the interval from the screenshot size instead of using `interval`
+    pub auto_interval: bool,
+    pub style: GridStyle,
+    /// Line color as RGBA, the alpha channel is blended over the screenshot
+    pub color: [u8; 4],
+    pub thickness: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DisplayServer {
+    /// Invisible in-memory X server, for CI and servers without a screen
+    Xvfb,
+    /// X server nested in a window on the current display, to watch the agent work
+    Xephyr,
+}
+
+#[derive(Clone, Debug)]
+pub struct VirtualDisplayOptions {
+    pub server: DisplayServer,
+    pub width: u32,
+    pub height: u32,
+    /// Command started before the other applications, usually a window manager
+    pub window_manager: Option<String>,
+    /// Shell commands started on the display once it is ready
+    pub startup: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScreenCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// A key combination such as `ctrl+alt+q`, as lowercase key names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hotkey {
+    pub keys: Vec<String>,
+}
+
+/// What the user could have changed between two steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserActivity {
+    pub pointer: (i32, i32),
+    pub active_window: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum RedactionStyle {
+    /// Fill the area with black
+    Black,
+    /// Blur the area
+    Blur,
+}
+
+/// An area hidden from the model, in pixels of the screenshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redaction {
+    /// `region`, `window "<title>"` or `text matching <pattern>`
+    pub reason: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskLevel {
+    Low,
+    /// Changes that are usually easy to undo, like saving or closing
+    Medium,
+    /// Destructive or irreversible actions, like deleting, sending or paying
+    High,
+}
+
+/// How risky an action looks and why.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RiskAssessment {
+    pub level: RiskLevel,
+    pub reasons: Vec<String>,
+}
+
+/// When the screen counts as settled after an action.
+#[derive(Clone, Copy, Debug)]
+pub struct StabilityOptions {
+    /// Fraction of pixels that may differ between two frames of a stable screen
+    pub threshold: f32,
+    /// Number of consecutive similar frames needed
+    pub frames: u32,
+    pub poll_interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+/// A rectangle in screen coordinates, the same ones used to move the mouse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x
+            && y >= self.y
+            && x < self.x + self.width as i32
+            && y < self.y + self.height as i32
+    }
+}
+
+/// Rules loaded from a policy file, every rule is optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyRules {
+    /// Action types that are never executed, e.g. `SEARCH`
+    pub deny_actions: Vec<String>,
+    /// Regexes for text that must not be typed
+    pub deny_type_patterns: Vec<String>,
+    /// When set, SEARCH may only open these applications
+    pub allowed_apps: Option<Vec<String>>,
+    /// When set, URLs may only be typed for these domains and their subdomains
+    pub allowed_domains: Option<Vec<String>>,
+    /// Screen rectangles where clicks are refused
+    pub forbidden_regions: Vec<Region>,
+    /// Windows, by part of their title, where clicks are refused
+    pub forbidden_windows: Vec<String>,
+}
+
+/// A top level window as reported by the window manager.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub title: String,
+    pub region: Region,
+}
+
+/// The part of the screen the agent is limited to.
+#[derive(Clone, Debug)]
+pub enum RegionTarget {
+    Rect(Region),
+    /// The first window whose title contains this text, looked up again on every step
+    Window(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub grid: GridOptions,
+    pub annotation: Annotation,
+    pub refine: bool,
+    pub zoom_factor: u32,
+    pub zoom_crop_size: u32,
+    pub stability: StabilityOptions,
+    pub region: Option<RegionTarget>,
+    /// Ask the user before executing every action
+    pub confirm: bool,
+    /// Validate and log actions without sending any input
+    pub dry_run: bool,
+    pub policy: Option<crate::policy::Policy>,
+    /// Pause when the pointer or focused window changed without the agent
+    pub pause_on_takeover: bool,
+    /// Resume a paused run once the user was idle this long instead of waiting for Enter
+    pub resume_after_idle_ms: Option<u64>,
+    /// Secrets the model can type by placeholder
+    pub secrets: crate::secrets::Secrets,
+    pub redactor: Option<crate::redact::Redactor>,
+    /// Ask before actions at or above this risk level, even without `confirm`
+    pub confirm_risk: Option<RiskLevel>,
+    /// Read the text around click targets to assess their risk
+    pub risk_ocr: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Mark {
+    pub id: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Mark {
+    pub fn center(&self) -> (u32, u32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+}
+
+/// What an action did, `message` is reported to the model.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActionResult {
+    /// Whether any input was sent to the computer
+    pub executed: bool,
+    pub message: String,
+}
+
+impl ActionResult {
+    pub fn executed(message: String) -> Self {
+        ActionResult {
+            executed: true,
+            message,
+        }
+    }
+
+    pub fn not_executed(message: String) -> Self {
+        ActionResult {
+            executed: false,
+            message,
+        }
+    }
+}
+
+/// Where the previous action landed, drawn on the next screenshot so the model can check it.
+/// Positions are fractions (0.0 to 1.0) of the screen width and height.
+#[derive(Clone, Debug)]
+pub enum PreviousAction {
+    Click {
+        x: f32,
+        y: f32,
+    },
+    /// Text typed into the element focused by the last click, if there was one
+    Type {
+        text: String,
+        focus: Option<(f32, f32)>,
+    },
+}
+
+/// What happened on the screen after an action.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ScreenChange {
+    /// The screen differs from the frame captured right before the action
+    pub changed: bool,
+    /// The screen stopped changing before the timeout
+    pub stable: bool,
+    pub waited_ms: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PreviousStep {
+    pub action: Option<PreviousAction>,
+    pub screen_change: Option<ScreenChange>,
+    /// Told to the model when its previous action was not executed as it asked
+    pub feedback: Option<String>,
+}
+
+/// A screenshot saved by `capture_screen`.
+#[derive(Clone, Copy, Debug)]
+pub struct Capture {
+    /// Mouse pointer position in pixels of the screenshot, `None` when it is outside of it
+    pub pointer: Option<(u32, u32)>,
+    /// Captured pixels per screen coordinate
+    pub scale: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Observation {
+    pub screenshot_path: String,
+    pub annotated_path: String,
+    pub width: u32,
+    pub height: u32,
+    pub marks: Vec<Mark>,
+    pub grid: Option<GridOptions>,
+    /// Mouse pointer position in pixels of the screenshot
+    pub pointer: Option<(u32, u32)>,
+    pub previous_step: Option<PreviousStep>,
+    /// The region the screenshot was cropped to, percentages are relative to it
+    pub region: Option<Region>,
+    /// Open windows without the redacted ones, empty when the platform can't list them
+    pub windows: Vec<WindowInfo>,
+    pub focused_window: Option<u32>,
+    /// Areas hidden before the screenshot was annotated
+    pub redactions: Vec<Redaction>,
+}
+
+/// Token counts reported in the `usage` field of a chat completion.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// Estimated from the image sizes, part of the prompt tokens
+    #[serde(default)]
+    pub image_tokens: u64,
+    /// Estimated from the price table, `None` when the model has no price
+    #[serde(default)]
+    pub cost_usd: Option<f64>,
+}
+
+/// Price of a model in USD per million tokens.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Limits on the tokens and money a run may spend.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub max_tokens: Option<u64>,
+    pub max_cost_usd: Option<f64>,
+}
+
+/// One request to the model and its raw answer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelCall {
+    pub prompt: String,
+    pub response: String,
+    pub duration_ms: u64,
+    pub usage: Option<TokenUsage>,
+}
+
+/// The outcome of refining a click on a zoomed-in screenshot.
+#[derive(Clone, Debug)]
+pub struct Refinement {
+    pub click_detail: serde_json::Value,
+    pub call: ModelCall,
+    pub zoom_path: String,
+    pub zoom_annotated_path: String,
+}
+
+/// Metadata of a recorded run, stored as `run.json` next to the trajectory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunInfo {
+    pub schema_version: u32,
+    pub objective: String,
+    pub started_at_ms: u64,
+    pub finished_at_ms: Option<u64>,
+    /// `done`, `max_steps`, `budget_exceeded`, `unknown_action`, `aborted`, `aborted: <reason>`,
+    /// `error: ...` or `interrupted`
+    pub outcome: Option<String>,
+    /// Summary of the progress made, written when the run was stopped before it was done
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StepTimings {
+    /// Capturing and annotating the screenshot
+    pub observe_ms: u64,
+    pub model_ms: u64,
+    pub refine_ms: u64,
+    pub act_ms: u64,
+    /// Waiting for the screen to settle after the action
+    pub settle_ms: u64,
+}
+
+/// What the user decided about an action in confirm mode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "choice", rename_all = "snake_case")]
+pub enum Confirmation {
+    Approved,
+    Skipped,
+    /// The action of the step was replaced, this is the one the model asked for
+    Edited {
+        original_action: String,
+        original_detail: String,
+    },
+    Aborted,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefineRecord {
+    pub prompt: String,
+    pub response: String,
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+    pub zoom_screenshot: String,
+    pub zoom_annotated_screenshot: String,
+}
+
+/// One line of `trajectory.jsonl`. Image paths are relative to the run directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrajectoryStep {
+    pub step: u32,
+    pub started_at_ms: u64,
+    pub screenshot: String,
+    pub annotated_screenshot: String,
+    /// The screen right before the action, without the cursor and with the redacted areas
+    /// blacked out, for replays to compare the screen against
+    #[serde(default)]
+    pub frame: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub region: Option<Region>,
+    pub pointer: Option<(u32, u32)>,
+    pub marks: Vec<Mark>,
+    pub prompt: String,
+    pub response: String,
+    #[serde(default)]
+    pub usage: Option<TokenUsage>,
+    pub action: String,
+    pub action_detail: String,
+    /// The click that was performed, as percentages after resolving marks, cells and refinement
+    pub click: Option<serde_json::Value>,
+    pub refine: Option<RefineRecord>,
+    /// The user's decision when running with `--confirm`
+    #[serde(default)]
+    pub confirmation: Option<Confirmation>,
+    /// The screenshot with the intended action drawn on it, in dry runs
+    #[serde(default)]
+    pub intended_screenshot: Option<String>,
+    /// Why the policy refused the action
+    #[serde(default)]
+    pub policy_violation: Option<String>,
+    /// Areas hidden from the model on the screenshot
+    #[serde(default)]
+    pub redactions: Vec<Redaction>,
+    #[serde(default)]
+    pub risk: Option<RiskAssessment>,
+    pub result: String,
+    /// Whether input was sent to the computer, false for simulated, skipped and refused
+    /// actions. Recordings without it count as not executed, so replays never send input a
+    /// recording may not have sent
+    #[serde(default)]
+    pub executed: bool,
+    pub screen_change: Option<ScreenChange>,
+    pub timings: StepTimings,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GifOptions {
+    pub frame_duration_ms: u32,
+    /// Factor applied to the size of the screenshots, 0.5 halves them
+    pub scale: f32,
+}
+
+/// Replaces the recorded action of a step when replaying.
+#[derive(Clone, Debug)]
+pub enum ActionOverride {
+    Skip,
+    /// An action in the form recorded in the trajectory, e.g. `TYPE` with the text or
+    /// `CLICK` with a JSON object holding `x` and `y` percentages
+    Replace {
+        action: String,
+        detail: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    /// Ask before every step
+    pub step_manually: bool,
+    /// Stop when a larger fraction of the screen differs from the recorded screenshot
+    pub max_divergence: Option<f32>,
+    pub overrides: Vec<(u32, ActionOverride)>,
+    pub secrets: crate::secrets::Secrets,
+    /// Steps whose action the policy refuses are skipped
+    pub policy: Option<crate::policy::Policy>,
+    pub stability: StabilityOptions,
+}
+
+/// Where an input event goes.
+#[derive(Clone, Debug, Default)]
+pub struct InputTarget {
+    /// Click position in screen coordinates
+    pub position: Option<(i32, i32)>,
+    /// Title of the window that receives the input
+    pub window: Option<String>,
+}
+
+/// One executed input event in the audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub action: String,
+    pub detail: String,
+    pub position: Option<(i32, i32)>,
+    pub window: Option<String>,
+    pub screenshot_sha256: String,
+    pub response_sha256: Option<String>,
+    pub result: String,
+    /// Hash of the previous entry, zeros for the first one
+    pub prev_hash: String,
+}
+
+/// A line of the audit log, `hash` is the SHA-256 of the serialized record.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(flatten)]
+    pub record: AuditRecord,
+    pub hash: String,
+}
+
+/// The number of entries and last hash of an audit log, kept next to it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditHead {
+    pub entries: u64,
+    pub last_hash: String,
+}
@@ -0,0 +1,297 @@
+use crate::types::{Region, RegionTarget, WindowInfo};
+
+#[cfg(target_os = "linux")]
+use x11 as platform;
+
+#[cfg(not(target_os = "linux"))]
+use unsupported as platform;
+
+pub use platform::{
+    active_window, close_window, focus_window, list_windows, minimize_window, move_resize_window,
+};
+
+/// Finds the topmost window whose title contains `title`, ignoring case.
+pub fn find_window(title: &str) -> Result<WindowInfo, String> {
+    let title = title.to_lowercase();
+    list_windows()?
+        .into_iter()
+        .rev()
+        .find(|window| window.title.to_lowercase().contains(&title))
+        .ok_or_else(|| format!("No window with a title containing \"{}\"", title))
+}
+
+/// The topmost of `windows`, as listed by `list_windows`, at a screen position.
+pub fn window_at(windows: &[WindowInfo], x: i32, y: i32) -> Option<&WindowInfo> {
+    windows
+        .iter()
+        .rev()
+        .find(|window| window.region.contains(x, y))
+}
+
+/// The current rectangle of a region target. Windows are looked up every time since they
+/// can move between steps.
+pub fn resolve_region(target: Option<&RegionTarget>) -> Result<Option<Region>, String> {
+    match target {
+        Some(RegionTarget::Rect(region)) => Ok(Some(*region)),
+        Some(RegionTarget::Window(title)) => Ok(Some(find_window(title)?.region)),
+        None => Ok(None),
+    }
+}
+
+/// Window management through EWMH, works with any EWMH compliant window manager on a local
+/// X server (including Xvfb).
+#[cfg(target_os = "linux")]
+mod x11 {
+    use crate::types::{Region, WindowInfo};
+    use x11rb::{
+        connection::Connection,
+        protocol::xproto::{
+            Atom, AtomEnum, ClientMessageEvent, ConnectionExt as _, EventMask, Window,
+        },
+        rust_connection::RustConnection,
+    };
+
+    // Source indication for EWMH requests: 2 means a pager or other direct user action
+    const SOURCE_PAGER: u32 = 2;
+    // ICCCM WM_CHANGE_STATE value to iconify a window
+    const ICONIC_STATE: u32 = 3;
+
+    fn x11_error(e: impl std::fmt::Display) -> String {
+        format!("X11 request failed: {}", e)
+    }
+
+    fn connect() -> Result<(RustConnection, Window), String> {
+        let (conn, screen_num) = x11rb::connect(None)
+            .map_err(|e| format!("Failed to connect to the X server: {}", e))?;
+        let root = conn.setup().roots[screen_num].root;
+        Ok((conn, root))
+    }
+
+    fn atom(conn: &RustConnection, name: &str) -> Result<Atom, String> {
+        Ok(conn
+            .intern_atom(false, name.as_bytes())
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?
+            .atom)
+    }
+
+    /// Sends a client message about `window` to the window manager.
+    fn send_to_window_manager(
+        conn: &RustConnection,
+        root: Window,
+        window: Window,
+        message: &str,
+        data: [u32; 5],
+    ) -> Result<(), String> {
+        let event = ClientMessageEvent::new(32, window, atom(conn, message)?, data);
+        conn.send_event(
+            false,
+            root,
+            EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+            event,
+        )
+        .map_err(x11_error)?
+        .check()
+        .map_err(x11_error)?;
+        conn.flush().map_err(x11_error)
+    }
+
+    /// Top level windows managed by an EWMH compliant window manager, in stacking order from
+    /// bottom to top.
+    pub fn list_windows() -> Result<Vec<WindowInfo>, String> {
+        let (conn, root) = connect()?;
+
+        let client_list = atom(&conn, "_NET_CLIENT_LIST_STACKING")?;
+        let reply = conn
+            .get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+        let windows: Vec<Window> = reply
+            .value32()
+            .ok_or("The window manager does not publish _NET_CLIENT_LIST_STACKING")?
+            .collect();
+
+        // A window can close between reading the list and querying it, it is left out
+        Ok(windows
+            .into_iter()
+            .filter_map(|id| {
+                Some(WindowInfo {
+                    id,
+                    title: window_title(&conn, id).ok()?,
+                    region: window_region(&conn, root, id).ok()?,
+                })
+            })
+            .collect())
+    }
+
+    /// The window that has the keyboard focus according to the window manager.
+    pub fn active_window() -> Result<Option<u32>, String> {
+        let (conn, root) = connect()?;
+
+        let active_window = atom(&conn, "_NET_ACTIVE_WINDOW")?;
+        let reply = conn
+            .get_property(false, root, active_window, AtomEnum::WINDOW, 0, 1)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+
+        Ok(reply
+            .value32()
+            .and_then(|mut value| value.next())
+            .filter(|id| *id != 0))
+    }
+
+    pub fn focus_window(id: u32) -> Result<(), String> {
+        let (conn, root) = connect()?;
+        send_to_window_manager(
+            &conn,
+            root,
+            id,
+            "_NET_ACTIVE_WINDOW",
+            [SOURCE_PAGER, 0, 0, 0, 0],
+        )
+    }
+
+    /// Moves and/or resizes a window, `None` leaves that value unchanged.
+    pub fn move_resize_window(
+        id: u32,
+        x: Option<i32>,
+        y: Option<i32>,
+        width: Option<u32>,
+        height: Option<u32>,
+    ) -> Result<(), String> {
+        let (conn, root) = connect()?;
+
+        // Bits 8 to 11 tell which of x, y, width and height are set, gravity 0 keeps the
+        // window's own gravity
+        let mut flags = SOURCE_PAGER << 12;
+        for (bit, value) in [x.is_some(), y.is_some(), width.is_some(), height.is_some()]
+            .into_iter()
+            .enumerate()
+        {
+            if value {
+                flags |= 1 << (8 + bit);
+            }
+        }
+
+        send_to_window_manager(
+            &conn,
+            root,
+            id,
+            "_NET_MOVERESIZE_WINDOW",
+            [
+                flags,
+                x.unwrap_or_default() as u32,
+                y.unwrap_or_default() as u32,
+                width.unwrap_or_default(),
+                height.unwrap_or_default(),
+            ],
+        )
+    }
+
+    pub fn minimize_window(id: u32) -> Result<(), String> {
+        let (conn, root) = connect()?;
+        send_to_window_manager(
+            &conn,
+            root,
+            id,
+            "WM_CHANGE_STATE",
+            [ICONIC_STATE, 0, 0, 0, 0],
+        )
+    }
+
+    pub fn close_window(id: u32) -> Result<(), String> {
+        let (conn, root) = connect()?;
+        send_to_window_manager(
+            &conn,
+            root,
+            id,
+            "_NET_CLOSE_WINDOW",
+            [0, SOURCE_PAGER, 0, 0, 0],
+        )
+    }
+
+    fn window_title(conn: &RustConnection, window: Window) -> Result<String, String> {
+        let net_wm_name = atom(conn, "_NET_WM_NAME")?;
+        let utf8_string = atom(conn, "UTF8_STRING")?;
+
+        for (property, type_) in [
+            (net_wm_name, utf8_string),
+            (AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()),
+        ] {
+            let reply = conn
+                .get_property(false, window, property, type_, 0, u32::MAX)
+                .map_err(x11_error)?
+                .reply()
+                .map_err(x11_error)?;
+            if !reply.value.is_empty() {
+                return Ok(String::from_utf8_lossy(&reply.value).into_owned());
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    fn window_region(
+        conn: &RustConnection,
+        root: Window,
+        window: Window,
+    ) -> Result<Region, String> {
+        let geometry = conn
+            .get_geometry(window)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+        let position = conn
+            .translate_coordinates(window, root, 0, 0)
+            .map_err(x11_error)?
+            .reply()
+            .map_err(x11_error)?;
+
+        Ok(Region {
+            x: position.dst_x as i32,
+            y: position.dst_y as i32,
+            width: geometry.width as u32,
+            height: geometry.height as u32,
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use crate::types::WindowInfo;
+
+    const UNSUPPORTED: &str = "Window management is only supported on X11";
+
+    pub fn list_windows() -> Result<Vec<WindowInfo>, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn active_window() -> Result<Option<u32>, String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn focus_window(_id: u32) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn move_resize_window(
+        _id: u32,
+        _x: Option<i32>,
+        _y: Option<i32>,
+        _width: Option<u32>,
+        _height: Option<u32>,
+    ) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn minimize_window(_id: u32) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+
+    pub fn close_window(_id: u32) -> Result<(), String> {
+        Err(UNSUPPORTED.to_string())
+    }
+}